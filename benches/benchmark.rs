@@ -2,9 +2,11 @@ use criterion::criterion_group;
 use criterion::criterion_main;
 use criterion::Criterion;
 
-use particle::automatons::DFA;
+use std::fs;
+
+use particle::automatons::{DenseDFA, StateId, DFA, NFA};
 use particle::define_lexer;
-use particle::lexer::LexerState;
+use particle::lexer::{LexerState, SliceState};
 use particle::regex;
 use particle::span::Span;
 
@@ -44,8 +46,92 @@ fn bench_all(c: &mut Criterion) {
     });
 }
 
+fn bench_dfa_matching(c: &mut Criterion) {
+    let nfa = regex::compile_regex(r#"\"([^\\\"]|\\.)*\""#).unwrap();
+    let dfa = DFA::from(nfa).minimize();
+    let table = DenseDFA::from(&dfa);
+    let input = format!("\"{}\"", "lorem \\\"ipsum\\\" dolor sit amet ".repeat(4096));
+    c.bench_function("DFA Matching", |b| b.iter(|| dfa.accepts(input.bytes())));
+    c.bench_function("Dense DFA Matching", |b| {
+        b.iter(|| table.accepts(input.bytes()))
+    });
+}
+
+/// The rules of the JSON lexer in `bench_lexer`.
+const JSON_RULES: [&str; 6] = [
+    r#"[ \n\r\t]+"#,
+    r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f]{4}))*""#,
+    r#"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][\+\-]?[0-9]+)?"#,
+    r#"[{}\[\],:]"#,
+    r#"true|false"#,
+    r#"null"#,
+];
+
+/// Splits `input` into longest matches like the lexer does, with `next` giving the transitions
+/// of a DFA (`None` where there is none) and `accepts` its final states. Returns the number of
+/// tokens.
+fn longest_matches<N, A>(input: &[u8], initial_state: StateId, next: N, accepts: A) -> usize
+where
+    N: Fn(StateId, u8) -> Option<StateId>,
+    A: Fn(StateId) -> bool,
+{
+    let mut count = 0;
+    let mut pos = 0;
+    while pos < input.len() {
+        let mut state = initial_state;
+        let mut last_accepted = None;
+        for (i, &b) in input[pos..].iter().enumerate() {
+            match next(state, b) {
+                Some(to) => state = to,
+                None => break,
+            }
+            if accepts(state) {
+                last_accepted = Some(pos + i + 1);
+            }
+        }
+        match last_accepted {
+            Some(end) => pos = end,
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
+
+/// The matching loop of the JSON lexer on the hash map transitions of `DFA`, which the lexer
+/// used before, and on the table of `DenseDFA`, which it uses now.
+fn bench_lexer_tables(c: &mut Criterion) {
+    let nfa = JSON_RULES
+        .iter()
+        .map(|rule| regex::compile_regex(rule).unwrap())
+        .fold(NFA::new(), |rules, rule| rules | rule);
+    let dfa = DFA::from(nfa).minimize();
+    let table = DenseDFA::from(&dfa);
+    let contents = fs::read_to_string("benches/large_json.json").unwrap();
+    let input = contents.as_bytes();
+    c.bench_function("Lexer Loop (DFA)", |b| {
+        b.iter(|| {
+            longest_matches(
+                input,
+                dfa.initial_state,
+                |state, b| dfa.transitions.get(&(state, b)).cloned(),
+                |state| dfa.final_states.contains_key(&state),
+            )
+        })
+    });
+    c.bench_function("Lexer Loop (Dense DFA)", |b| {
+        b.iter(|| {
+            longest_matches(
+                input,
+                table.initial_state,
+                |state, b| Some(table.next(state, b)).filter(|&to| to != DenseDFA::DEAD),
+                |state| table.branch(state).is_some(),
+            )
+        })
+    });
+}
+
 fn bench_lexer(c: &mut Criterion) {
-    #[derive(Debug)]
     enum TokenKind {
         Number(f64),
         Str(String),
@@ -54,7 +140,6 @@ fn bench_lexer(c: &mut Criterion) {
         Null,
     }
 
-    #[derive(Debug)]
     struct Token {
         span: Span,
         kind: TokenKind,
@@ -64,45 +149,66 @@ fn bench_lexer(c: &mut Criterion) {
         fn from(span: Span, kind: TokenKind) -> Self {
             Token { span, kind }
         }
+
+        /// Sums up the token, so that the work of the handlers cannot be optimized away.
+        fn checksum(&self) -> usize {
+            self.span.from.offset
+                + match &self.kind {
+                    TokenKind::Number(n) => *n as usize,
+                    TokenKind::Str(s) | TokenKind::Punctuation(s) => s.len(),
+                    TokenKind::Bool(b) => *b as usize,
+                    TokenKind::Null => 0,
+                }
+        }
     }
 
     let lexer = define_lexer!(Token =
-        discard r#"[ \n\r\t]+"#,
-        r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f][0-9a-f][0-9a-f][0-9a-f]))*""# =>
-            |s, span| Token::from(span, TokenKind::Str(String::from(s))),
-        r#"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][\+\-]?[0-9]+)?"# =>
-            |s, span| Token::from(span, TokenKind::Number(s.parse().unwrap())),
-        r#"[{}\[\],:]"# =>
-            |s, span| Token::from(span, TokenKind::Punctuation(String::from(s))),
-        r#"true|false"# =>
-            |s, span| Token::from(span, TokenKind::Bool(s.parse().unwrap())),
-        r#"null"# =>
-            |_, span| Token::from(span, TokenKind::Null)
+        discard JSON_RULES[0],
+        JSON_RULES[1] => |s, span| Token::from(span, TokenKind::Str(String::from(s))),
+        JSON_RULES[2] => |s, span| Token::from(span, TokenKind::Number(s.parse().unwrap())),
+        JSON_RULES[3] => |s, span| Token::from(span, TokenKind::Punctuation(String::from(s))),
+        JSON_RULES[4] => |s, span| Token::from(span, TokenKind::Bool(s.parse().unwrap())),
+        JSON_RULES[5] => |_, span| Token::from(span, TokenKind::Null)
     );
 
+    let contents = fs::read_to_string("benches/large_json.json").unwrap();
     c.bench_function("Lexer", |b| {
         b.iter(|| {
-            use std::fs;
-            let contents = fs::read_to_string("benches/large_json.json").unwrap();
             let mut state = LexerState::from(contents.chars());
             let mut cnt = 0usize;
             while !state.eof() {
-                if let Ok(_) = lexer.next_token(&mut state) {
-                    cnt += 1;
-                } else {
-                    break;
+                match lexer.next_token(&mut state) {
+                    Ok(token) => cnt += token.checksum(),
+                    Err(_) => break,
+                }
+            }
+            cnt
+        })
+    });
+
+    c.bench_function("Lexer (Slice)", |b| {
+        b.iter(|| {
+            let mut state = SliceState::from(contents.as_str());
+            let mut cnt = 0usize;
+            while !state.eof() {
+                match lexer.next_token_slice(&mut state) {
+                    Ok(token) => cnt += token.checksum(),
+                    Err(_) => break,
                 }
             }
+            cnt
         })
     });
 }
 
 criterion_group!(
     benches,
-    // bench_regex_to_nfa,
-    // bench_nfa_to_dfa,
-    // bench_dfa_minimize,
-    // bench_all,
+    bench_regex_to_nfa,
+    bench_nfa_to_dfa,
+    bench_dfa_minimize,
+    bench_all,
+    bench_dfa_matching,
+    bench_lexer_tables,
     bench_lexer
 );
 criterion_main!(benches);
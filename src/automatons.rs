@@ -3,7 +3,7 @@ DFAs and NFAs with related algorithms.
 
 # Example
 Consider the following regex matching float literals:
-```
+```text
 [1-9][0-9]*(\.[0-9]+)?([eE](\+|-)?[1-9][0-9]*)?
 ```
 The NFA of this regex can be constructed as follow:
@@ -25,10 +25,14 @@ let nfa = int_part & float_part.optional() & exp_part.optional();
 ```
 We can convert this NFA to DFA by:
 ```
+# use particle::automatons::{NFA, DFA};
+# let nfa = NFA::from(('0', '9'));
 let dfa = DFA::from(nfa);
 ```
 and minimize it by:
 ```
+# use particle::automatons::{NFA, DFA};
+# let dfa = DFA::from(NFA::from(('0', '9')));
 let dfa = dfa.minimize();
 ```
 //!
@@ -38,8 +42,10 @@ script of the automaton.
 */
 
 use std::cmp;
-use std::collections::BTreeSet;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::*;
+use std::iter;
 use std::ops::{BitAnd, BitOr};
 
 use indexmap::IndexSet;
@@ -60,6 +66,18 @@ pub type StateSet = BTreeSet<StateId>;
 // Default branch number for final states whose branch number is not explicitly specified
 const DEFAULT_BRANCH_ID: BranchId = 0;
 
+/// Input byte standing for the start of a line (`^` in regex).
+///
+/// It never occurs in UTF-8, the lexer feeds it to the DFA before the first char of a token
+/// starting a line.
+pub const LINE_START: u8 = 0xff;
+
+/// Input byte standing for the end of a line (`$` in regex).
+///
+/// It never occurs in UTF-8, the lexer feeds it to the DFA after the last char of a token
+/// followed by a line break or the end of input.
+pub const LINE_END: u8 = 0xfe;
+
 /// Non-deterministic Finite Automaton.
 ///
 /// The inside implementation of the automaton is based on `u8`,
@@ -70,6 +88,8 @@ pub struct NFA {
     pub initial_state: StateId,
     pub final_states: FxHashMap<StateId, BranchId>,
     pub transitions: MultiMap<(StateId, Transition), StateId>,
+    /// States where the trailing context of a branch starts, see `followed_by`.
+    pub context_states: FxHashMap<StateId, BranchId>,
 }
 
 impl From<&str> for NFA {
@@ -104,24 +124,77 @@ impl From<char> for NFA {
     }
 }
 
+impl From<u8> for NFA {
+    /// Constructs the NFA from a single byte.
+    fn from(b: u8) -> Self {
+        let mut ret = NFA::new();
+        ret.transitions.insert((0, Transition::Input(b)), 1);
+        ret.final_states.insert(1, DEFAULT_BRANCH_ID);
+        ret
+    }
+}
+
+impl From<&[(u8, u8)]> for NFA {
+    /// Constructs the NFA accepting a single byte in any of the intervals, bytes of 0x80 and
+    /// above are matched as they are instead of as UTF-8.
+    fn from(intervals: &[(u8, u8)]) -> Self {
+        let mut ret = NFA::new();
+        for &(l, r) in intervals {
+            for b in l..=r {
+                ret.transitions.insert((0, Transition::Input(b)), 1);
+            }
+        }
+        ret.final_states.insert(1, DEFAULT_BRANCH_ID);
+        ret
+    }
+}
+
 impl From<(char, char)> for NFA {
     /// Constructs the NFA from a char interval.
     fn from(interval: (char, char)) -> Self {
+        NFA::from(&[interval][..])
+    }
+}
+
+impl From<&[(char, char)]> for NFA {
+    /// Constructs the NFA accepting a char in any of the intervals.
+    ///
+    /// The UTF-8 sequences of all intervals share one final state, and so do the states
+    /// from which the remaining byte ranges are the same. This keeps the NFA small even for
+    /// large Unicode classes, where most sequences end with the same continuation bytes.
+    fn from(intervals: &[(char, char)]) -> Self {
         use utf8_ranges::Utf8Sequences;
 
         let mut ret = NFA::new();
-        let mut next_id = 1;
-        for seq in Utf8Sequences::new(interval.0, interval.1) {
-            let mut last = 0;
-            for r in seq.into_iter() {
-                for b in r.start..=r.end {
-                    ret.transitions
-                        .insert((last, Transition::Input(b)), next_id);
+        let final_state = 1;
+        ret.final_states.insert(final_state, DEFAULT_BRANCH_ID);
+        // States by the byte ranges remaining to be read from them
+        let mut suffixes: FxHashMap<Vec<(u8, u8)>, StateId> = FxHashMap::default();
+        suffixes.insert(Vec::new(), final_state);
+        let mut next_id = 2;
+        for &(l, r) in intervals {
+            for seq in Utf8Sequences::new(l, r) {
+                let ranges: Vec<(u8, u8)> = seq.into_iter().map(|r| (r.start, r.end)).collect();
+                // Build the sequence backwards, reusing the states of shared suffixes
+                let mut to = final_state;
+                for i in (1..ranges.len()).rev() {
+                    to = match suffixes.get(&ranges[i..]) {
+                        Some(&state) => state,
+                        None => {
+                            let state = next_id;
+                            next_id += 1;
+                            for b in ranges[i].0..=ranges[i].1 {
+                                ret.transitions.insert((state, Transition::Input(b)), to);
+                            }
+                            suffixes.insert(ranges[i..].to_vec(), state);
+                            state
+                        }
+                    };
+                }
+                for b in ranges[0].0..=ranges[0].1 {
+                    ret.transitions.insert((0, Transition::Input(b)), to);
                 }
-                last = next_id;
-                next_id += 1;
             }
-            ret.final_states.insert(last, DEFAULT_BRANCH_ID);
         }
         ret
     }
@@ -146,6 +219,7 @@ impl From<DFA> for NFA {
 impl BitAnd for NFA {
     type Output = NFA;
     //noinspection RsBorrowChecker
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn bitand(self, rhs: NFA) -> NFA {
         let mut ret = self;
         let bias = ret.max_state_id() + 1;
@@ -172,6 +246,8 @@ impl BitAnd for NFA {
                         .map(move |to| ((from + bias, *trans), to + bias))
                 }),
         );
+        ret.context_states
+            .extend(rhs.context_states.iter().map(|(x, &br)| (x + bias, br)));
         // Wow IntelliJ Rust is giving me an error on this
         // Certainly a bug since this passes compilation
         ret
@@ -186,6 +262,8 @@ impl BitOr for NFA {
         // Final states from either NFAs are final states of the result NFA.
         ret.final_states
             .extend(rhs.final_states.iter().map(|(x, &br)| (x + bias, br)));
+        ret.context_states
+            .extend(rhs.context_states.iter().map(|(x, &br)| (x + bias, br)));
         // Add biased transition edges.
         ret.transitions.extend(
             rhs.transitions
@@ -223,16 +301,17 @@ impl NFA {
             initial_state: 0,
             final_states: FxHashMap::default(),
             transitions: MultiMap::new(),
+            context_states: FxHashMap::default(),
         }
     }
 
     /// Max state id of the NFA, used for biasing when merging one NFA into the other.
     fn max_state_id(&self) -> StateId {
         self.transitions
-            .iter()
-            .map(|(&(from, _), &to)| cmp::max(from, to))
-            .max()
-            .unwrap_or(0)
+            .iter_all()
+            .flat_map(|(&(from, _), to_vec)| to_vec.iter().map(move |&to| cmp::max(from, to)))
+            .chain(self.final_states.keys().cloned())
+            .fold(self.initial_state, cmp::max)
     }
 
     /// Calculates the epsilon closure of a state.
@@ -240,8 +319,7 @@ impl NFA {
         let mut ret = StateSet::new();
         let mut stack = vec![s];
         ret.insert(s);
-        while !stack.is_empty() {
-            let u = stack.pop().unwrap();
+        while let Some(u) = stack.pop() {
             if let Some(vs) = self.transitions.get_vec(&(u, Transition::Epsilon)) {
                 for v in vs {
                     if !ret.contains(v) {
@@ -276,6 +354,21 @@ impl NFA {
         for br in self.final_states.values_mut() {
             *br = branch;
         }
+        for br in self.context_states.values_mut() {
+            *br = branch;
+        }
+    }
+
+    /// Matches `self` followed by the trailing context `context` (`r/s` in flex).
+    ///
+    /// The result accepts the same strings as `self & context`, but the final states of `self`
+    /// are remembered as context states. A DFA state containing one of them marks a position
+    /// where the part matched by `self` can end.
+    pub fn followed_by(self, context: NFA) -> NFA {
+        let mut ret = self;
+        let final_states = ret.final_states.clone();
+        ret.context_states.extend(final_states);
+        ret & context
     }
 
     /// Repeats `self` by >=0 times (`*` in regex).
@@ -299,6 +392,28 @@ impl NFA {
         self & temp
     }
 
+    /// Repeats `self` by at least `min` and at most `max` times (`{m,n}` in regex),
+    /// `max` being `None` means there is no upper bound (`{m,}` in regex).
+    ///
+    /// Every repetition is a separate copy of `self`, so the size of the result grows linearly
+    /// with the counts.
+    pub fn repeat(self, min: usize, max: Option<usize>) -> NFA {
+        let mut ret = NFA::from("");
+        for _ in 0..min {
+            ret = ret & self.clone();
+        }
+        match max {
+            None => ret & self.zero_or_more(),
+            Some(max) => {
+                let optional = self.optional();
+                for _ in min..max {
+                    ret = ret & optional.clone();
+                }
+                ret
+            }
+        }
+    }
+
     /// Makes `self` optional (0/1 times).
     pub fn optional(&self) -> NFA {
         let mut ret = self.clone();
@@ -316,12 +431,34 @@ impl NFA {
     }
 }
 
+/// Alphabet of the strings an automaton is considered over, which matters when taking the
+/// complement of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet {
+    /// Any byte, strings are arbitrary byte strings.
+    Bytes,
+    /// Any char, strings are valid UTF-8 byte strings.
+    Unicode,
+}
+
+impl Alphabet {
+    /// DFA accepting every string over the alphabet.
+    pub fn universe(self) -> DFA {
+        match self {
+            Alphabet::Bytes => DFA::new().complement(self),
+            Alphabet::Unicode => DFA::from(NFA::from(('\0', char::MAX)).zero_or_more()),
+        }
+    }
+}
+
 /// Deterministic Finite Automaton.
 #[derive(Clone)]
 pub struct DFA {
     pub initial_state: StateId,
     pub final_states: FxHashMap<StateId, FxHashSet<BranchId>>,
     pub transitions: FxHashMap<(StateId, u8), StateId>,
+    /// States containing context states of the NFA, with their branches.
+    pub context_states: FxHashMap<StateId, FxHashSet<BranchId>>,
 }
 
 impl From<NFA> for DFA {
@@ -343,16 +480,19 @@ impl From<NFA> for DFA {
 
         // The new initial state
         states.insert(initial_state, 0);
-        while !stack.is_empty() {
-            let state_now = stack.pop().unwrap();
+        while let Some(state_now) = stack.pop() {
             let idx = states[&state_now];
             // Character transitions coming out from all state in the state_now
             let mut edges_out_now: FxHashSet<u8> = FxHashSet::default();
             let mut branches = FxHashSet::default();
+            let mut context_branches = FxHashSet::default();
             for u in &state_now {
                 if let Some(&br) = nfa.final_states.get(u) {
                     branches.insert(br);
                 }
+                if let Some(&br) = nfa.context_states.get(u) {
+                    context_branches.insert(br);
+                }
                 if let Some(chs) = edges_out.get_vec(u) {
                     edges_out_now.extend(chs);
                 }
@@ -361,6 +501,9 @@ impl From<NFA> for DFA {
             if !branches.is_empty() {
                 ret.final_states.insert(idx, branches);
             }
+            if !context_branches.is_empty() {
+                ret.context_states.insert(idx, context_branches);
+            }
             for ch in edges_out_now {
                 let to = nfa.transition_set(&state_now, ch);
                 match states.get(&to) {
@@ -386,12 +529,42 @@ impl Default for DFA {
     }
 }
 
+/// The transitions of a DFA grouped by the state they leave, see `DFA::outgoing`.
+type Outgoing = FxHashMap<StateId, Vec<(u8, StateId)>>;
+
+/// A state of two DFAs run side by side, `None` standing for the dead state of a DFA.
+type StatePair = (Option<StateId>, Option<StateId>);
+
+/// The pairs reached from `pair` by the bytes on which at least one side has a transition, in
+/// byte order, `outgoing` being the outgoing transitions of both DFAs.
+fn pair_successors(outgoing: &(Outgoing, Outgoing), pair: StatePair) -> BTreeMap<u8, StatePair> {
+    let mut next: BTreeMap<u8, StatePair> = BTreeMap::new();
+    for &(b, to) in pair
+        .0
+        .and_then(|x| outgoing.0.get(&x))
+        .into_iter()
+        .flatten()
+    {
+        next.entry(b).or_insert((None, None)).0 = Some(to);
+    }
+    for &(b, to) in pair
+        .1
+        .and_then(|x| outgoing.1.get(&x))
+        .into_iter()
+        .flatten()
+    {
+        next.entry(b).or_insert((None, None)).1 = Some(to);
+    }
+    next
+}
+
 impl DFA {
     fn new() -> DFA {
         DFA {
             initial_state: 0,
             final_states: FxHashMap::default(),
             transitions: FxHashMap::default(),
+            context_states: FxHashMap::default(),
         }
     }
 
@@ -403,27 +576,307 @@ impl DFA {
             .unwrap_or(0)
     }
 
+    /// Whether the DFA accepts `input`.
+    pub fn accepts<I: IntoIterator<Item = u8>>(&self, input: I) -> bool {
+        let mut state = self.initial_state;
+        for b in input {
+            match self.transitions.get(&(state, b)) {
+                Some(&next) => state = next,
+                None => return false,
+            }
+        }
+        self.final_states.contains_key(&state)
+    }
+
+    /// The number of chars in every accepted string, if they all have the same number of chars.
+    ///
+    /// Returns `None` if the lengths differ or nothing is accepted.
+    pub fn fixed_length(&self) -> Option<usize> {
+        // Number of chars read to reach every state, bytes other than UTF-8 continuation bytes
+        // start a new char, except for the sentinels of anchors, which take no room
+        let mut lengths: FxHashMap<StateId, usize> = FxHashMap::default();
+        lengths.insert(self.initial_state, 0);
+        let mut stack = vec![self.initial_state];
+        let mut ret = None;
+        while let Some(u) = stack.pop() {
+            let len = lengths[&u];
+            if self.final_states.contains_key(&u) && ret.replace(len).is_some_and(|l| l != len) {
+                return None;
+            }
+            for b in 0..=u8::MAX {
+                if let Some(&v) = self.transitions.get(&(u, b)) {
+                    let zero_width = b & 0xc0 == 0x80 || b == LINE_START || b == LINE_END;
+                    let next_len = if zero_width { len } else { len + 1 };
+                    match lengths.get(&v) {
+                        Some(&l) if l != next_len => return None,
+                        Some(_) => {}
+                        None => {
+                            lengths.insert(v, next_len);
+                            stack.push(v);
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Transitions out of every state.
+    fn outgoing(&self) -> Outgoing {
+        let mut ret: Outgoing = FxHashMap::default();
+        for (&(from, b), &to) in &self.transitions {
+            ret.entry(from).or_default().push((b, to));
+        }
+        ret
+    }
+
+    /// Product construction of two DFAs, running both of them side by side.
+    ///
+    /// A state of the product is a pair of states, `None` standing for the dead state of a DFA
+    /// reached on a missing transition. The pair is final if `accept` says so given whether each
+    /// side is final. Pairs that can never be accepted are left out, so the result has missing
+    /// transitions too; when `accept(false, false)` holds, the pair of two dead states is kept
+    /// and every byte missing on both sides leads to it.
+    /// Branch information is lost, final states get the default branch.
+    fn product<F>(&self, other: &DFA, accept: F) -> DFA
+    where
+        F: Fn(bool, bool) -> bool,
+    {
+        // Whether a side can still be final, or only non-final if it is dead
+        let values = |state: Option<StateId>| {
+            if state.is_some() {
+                &[false, true][..]
+            } else {
+                &[false][..]
+            }
+        };
+        let hopeless = |(l, r): StatePair| {
+            !values(l)
+                .iter()
+                .any(|&a| values(r).iter().any(|&b| accept(a, b)))
+        };
+        let outgoing = (self.outgoing(), other.outgoing());
+        let mut ret = DFA::new();
+        let initial = (Some(self.initial_state), Some(other.initial_state));
+        let mut states: FxHashMap<StatePair, StateId> = FxHashMap::default();
+        states.insert(initial, 0);
+        let mut stack = vec![initial];
+        while let Some(pair) = stack.pop() {
+            let idx = states[&pair];
+            let is_final = |dfa: &DFA, state: Option<StateId>| {
+                state.is_some_and(|x| dfa.final_states.contains_key(&x))
+            };
+            if accept(is_final(self, pair.0), is_final(other, pair.1)) {
+                let mut branches = FxHashSet::default();
+                branches.insert(DEFAULT_BRANCH_ID);
+                ret.final_states.insert(idx, branches);
+            }
+            let mut next = pair_successors(&outgoing, pair);
+            if !hopeless((None, None)) {
+                for b in 0..=u8::MAX {
+                    next.entry(b).or_insert((None, None));
+                }
+            }
+            for (b, to) in next {
+                if hopeless(to) {
+                    continue;
+                }
+                let next_idx = states.len();
+                let to_idx = *states.entry(to).or_insert_with(|| {
+                    stack.push(to);
+                    next_idx
+                });
+                ret.transitions.insert((idx, b), to_idx);
+            }
+        }
+        ret.trim()
+    }
+
+    /// Removes the states from which no final state can be reached, except the initial one.
+    fn trim(self) -> DFA {
+        let mut reachable_from: MultiMap<StateId, StateId> = MultiMap::new();
+        for (&(from, _), &to) in &self.transitions {
+            reachable_from.insert(to, from);
+        }
+        let mut useful: FxHashSet<StateId> = self.final_states.keys().cloned().collect();
+        let mut stack: Vec<StateId> = useful.iter().cloned().collect();
+        while let Some(u) = stack.pop() {
+            for &v in reachable_from.get_vec(&u).into_iter().flatten() {
+                if useful.insert(v) {
+                    stack.push(v);
+                }
+            }
+        }
+        useful.insert(self.initial_state);
+        // Renumber the remaining states, keeping their order
+        let mut kept: Vec<StateId> = useful.into_iter().collect();
+        kept.sort();
+        let map: FxHashMap<StateId, StateId> = kept.iter().cloned().zip(0..).collect();
+        let renumber = |states: FxHashMap<StateId, FxHashSet<BranchId>>| {
+            states
+                .into_iter()
+                .filter_map(|(x, branches)| map.get(&x).map(|&x| (x, branches)))
+                .collect()
+        };
+        DFA {
+            initial_state: map[&self.initial_state],
+            transitions: self
+                .transitions
+                .iter()
+                .filter_map(|(&(from, b), to)| Some(((*map.get(&from)?, b), *map.get(to)?)))
+                .collect(),
+            final_states: renumber(self.final_states),
+            context_states: renumber(self.context_states),
+        }
+    }
+
+    /// DFA accepting the strings accepted by both `self` and `other`.
+    pub fn intersect(&self, other: &DFA) -> DFA {
+        self.product(other, |a, b| a && b)
+    }
+
+    /// DFA accepting the strings accepted by either `self` or `other`.
+    pub fn union(&self, other: &DFA) -> DFA {
+        self.product(other, |a, b| a || b)
+    }
+
+    /// DFA accepting the strings accepted by `self` but not by `other`.
+    pub fn difference(&self, other: &DFA) -> DFA {
+        self.product(other, |a, b| a && !b)
+    }
+
+    /// DFA accepting the strings over `alphabet` not accepted by `self`.
+    pub fn complement(&self, alphabet: Alphabet) -> DFA {
+        match alphabet {
+            // The product with the DFA accepting nothing completes `self` with a dead state
+            Alphabet::Bytes => self.product(&DFA::new(), |a, _| !a),
+            Alphabet::Unicode => alphabet.universe().difference(self),
+        }
+    }
+
+    /// Searches the product of `self` and `other` breadth first for a shortest string on which
+    /// `differ` holds given whether each side accepts it.
+    fn shortest_difference<F>(&self, other: &DFA, differ: F) -> Option<Counterexample>
+    where
+        F: Fn(bool, bool) -> bool,
+    {
+        let outgoing = (self.outgoing(), other.outgoing());
+        let initial = (Some(self.initial_state), Some(other.initial_state));
+        // The pair each pair was reached from and the byte read, to rebuild the string
+        let mut parents: FxHashMap<_, Option<(_, u8)>> = FxHashMap::default();
+        parents.insert(initial, None);
+        let mut queue = VecDeque::new();
+        queue.push_back(initial);
+        while let Some(pair) = queue.pop_front() {
+            let is_final = |dfa: &DFA, state: Option<StateId>| {
+                state.is_some_and(|x| dfa.final_states.contains_key(&x))
+            };
+            let accepted = (is_final(self, pair.0), is_final(other, pair.1));
+            if differ(accepted.0, accepted.1) {
+                let mut input = Vec::new();
+                let mut now = pair;
+                while let Some((prev, b)) = parents[&now] {
+                    input.push(b);
+                    now = prev;
+                }
+                input.reverse();
+                return Some(Counterexample {
+                    input,
+                    accepted_by_self: accepted.0,
+                });
+            }
+            for (b, to) in pair_successors(&outgoing, pair) {
+                if let Entry::Vacant(entry) = parents.entry(to) {
+                    entry.insert(Some((pair, b)));
+                    queue.push_back(to);
+                }
+            }
+        }
+        None
+    }
+
+    /// A shortest input reaching every state reachable from the initial one, the smallest one in
+    /// byte order among those.
+    pub fn shortest_inputs(&self) -> FxHashMap<StateId, Vec<u8>> {
+        let outgoing = self.outgoing();
+        let mut ret: FxHashMap<StateId, Vec<u8>> = FxHashMap::default();
+        ret.insert(self.initial_state, Vec::new());
+        let mut queue = VecDeque::new();
+        queue.push_back(self.initial_state);
+        while let Some(u) = queue.pop_front() {
+            let mut edges = outgoing.get(&u).cloned().unwrap_or_default();
+            edges.sort();
+            let prefix = ret[&u].clone();
+            for (b, v) in edges {
+                if let Entry::Vacant(entry) = ret.entry(v) {
+                    let mut input = prefix.clone();
+                    input.push(b);
+                    entry.insert(input);
+                    queue.push_back(v);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Checks whether `self` and `other` accept the same strings.
+    ///
+    /// Otherwise returns a shortest string accepted by one of them only, the smallest one in
+    /// byte order among those.
+    pub fn equivalent(&self, other: &DFA) -> std::result::Result<(), Counterexample> {
+        match self.shortest_difference(other, |a, b| a != b) {
+            Some(counterexample) => Err(counterexample),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks whether every string accepted by `self` is accepted by `other`.
+    ///
+    /// Otherwise returns a shortest string accepted by `self` only, the smallest one in byte
+    /// order among those.
+    pub fn is_subset_of(&self, other: &DFA) -> std::result::Result<(), Counterexample> {
+        match self.shortest_difference(other, |a, b| a && !b) {
+            Some(counterexample) => Err(counterexample),
+            None => Ok(()),
+        }
+    }
+
+    /// Minimizes the DFA with Hopcroft's algorithm.
+    ///
+    /// Final states are only merged when they carry exactly the same set of branches, so the
+    /// minimized DFA reports the same branches as the original one for every input. The same
+    /// holds for context states.
     pub fn minimize(self) -> DFA {
         let reachable_from: MultiMap<StateId, (u8, StateId)> = self
             .transitions
             .iter()
             .map(|(&(from, tr), &to)| (to, (tr, from)))
             .collect();
-        let all_states: StateSet = (0..=self.max_state_id()).collect();
         let mut partitions: IndexSet<StateSet> = IndexSet::new();
         let mut distinguishers: IndexSet<StateSet> = IndexSet::new();
-        let final_states: StateSet = all_states
-            .iter()
-            .filter(|x| self.final_states.contains_key(x))
-            .cloned()
-            .collect();
-        partitions.insert(final_states.clone());
-        partitions.insert(all_states.difference(&final_states).cloned().collect());
-        distinguishers.insert(all_states.difference(&final_states).cloned().collect());
-        distinguishers.insert(final_states);
+        // The initial partition groups states by their (sorted) branch sets and context branch
+        // sets, non-final states all share the empty one.
+        let sorted = |branches: Option<&FxHashSet<BranchId>>| {
+            let mut branches: Vec<BranchId> = branches
+                .map(|s| s.iter().cloned().collect())
+                .unwrap_or_default();
+            branches.sort();
+            branches
+        };
+        let mut by_branches: BTreeMap<_, StateSet> = BTreeMap::new();
+        for x in 0..=self.max_state_id() {
+            let key = (
+                sorted(self.final_states.get(&x)),
+                sorted(self.context_states.get(&x)),
+            );
+            by_branches.entry(key).or_default().insert(x);
+        }
+        for p in by_branches.into_values() {
+            partitions.insert(p.clone());
+            distinguishers.insert(p);
+        }
 
-        while !distinguishers.is_empty() {
-            let a = distinguishers.pop().unwrap();
+        while let Some(a) = distinguishers.pop() {
             let c: MultiMap<u8, StateId> = a
                 .iter()
                 .filter_map(|x| reachable_from.get_vec(x).map(|vec| vec.iter().cloned()))
@@ -432,8 +885,7 @@ impl DFA {
             for (_, x) in c.iter_all() {
                 let x: StateSet = x.iter().cloned().collect();
                 let mut new_partitions: IndexSet<StateSet> = IndexSet::new();
-                while !partitions.is_empty() {
-                    let y = partitions.pop().unwrap();
+                while let Some(y) = partitions.pop() {
                     let intersection: StateSet = y.intersection(&x).cloned().collect();
                     let difference: StateSet = y.difference(&x).cloned().collect();
                     if !intersection.is_empty() && !difference.is_empty() {
@@ -466,23 +918,20 @@ impl DFA {
                 p.iter().map(move |&x| (x, id))
             })
             .collect();
-        DFA {
-            initial_state: map[&self.initial_state],
-            final_states: partitions
+        // States in a partition all carry the same branches, the first one stands for all
+        let branches_of = |states: &FxHashMap<StateId, FxHashSet<BranchId>>| {
+            partitions
                 .iter()
                 .filter_map(|p| {
-                    let union: FxHashSet<BranchId> = p
-                        .iter()
-                        .filter_map(|x| self.final_states.get(x).map(|s| s.iter().cloned()))
-                        .flatten()
-                        .collect();
-                    if union.is_empty() {
-                        None
-                    } else {
-                        Some((labeled[p], union))
-                    }
+                    let first = p.iter().next()?;
+                    states.get(first).map(|s| (labeled[p], s.clone()))
                 })
-                .collect(),
+                .collect()
+        };
+        DFA {
+            initial_state: map[&self.initial_state],
+            final_states: branches_of(&self.final_states),
+            context_states: branches_of(&self.context_states),
             transitions: self
                 .transitions
                 .iter()
@@ -492,6 +941,181 @@ impl DFA {
     }
 }
 
+/// A string accepted by one DFA but not by the other, see `DFA::equivalent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    pub input: Vec<u8>,
+    /// Whether the string is accepted by the DFA the method was called on, rather than by the
+    /// other one.
+    pub accepted_by_self: bool,
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} is only accepted by the {} automaton",
+            String::from_utf8_lossy(&self.input),
+            if self.accepted_by_self {
+                "first"
+            } else {
+                "second"
+            }
+        )
+    }
+}
+
+impl std::error::Error for Counterexample {}
+
+/// A DFA compiled into a flat transition table, trading the flexibility of `DFA` for speed.
+///
+/// The 256 bytes are partitioned into classes of bytes that no state tells apart, and every
+/// state has a row in `transitions` with the target for each class. States are renumbered from
+/// 1, state `DEAD` (0) stands for a missing transition and never leaves itself.
+#[derive(Debug, Clone)]
+pub struct DenseDFA {
+    pub initial_state: StateId,
+    /// The class of every byte.
+    pub classes: [u8; 256],
+    /// The number of classes, i.e. the length of a row of `transitions`.
+    pub class_count: usize,
+    /// Targets of the transitions of state `s` on class `c` at `s * class_count + c`.
+    pub transitions: Vec<StateId>,
+    /// The lowest branch of every final state, indexed by state.
+    pub final_states: Vec<Option<BranchId>>,
+    /// States containing context states of the NFA, with their branches.
+    pub context_states: FxHashMap<StateId, FxHashSet<BranchId>>,
+}
+
+impl<'a> From<&'a DFA> for DenseDFA {
+    /// Compiles the DFA into a table.
+    fn from(dfa: &'a DFA) -> Self {
+        let mut states: Vec<StateId> = iter::once(dfa.initial_state)
+            .chain(
+                dfa.transitions
+                    .iter()
+                    .flat_map(|(&(from, _), &to)| vec![from, to]),
+            )
+            .chain(dfa.final_states.keys().cloned())
+            .chain(dfa.context_states.keys().cloned())
+            .collect();
+        states.sort_unstable();
+        states.dedup();
+        let ids: FxHashMap<StateId, StateId> = states
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| (x, i + 1))
+            .collect();
+        // Two bytes are in the same class if every state goes to the same state on them
+        let mut classes = [0u8; 256];
+        let mut columns: FxHashMap<Vec<StateId>, u8> = FxHashMap::default();
+        for b in 0..=255u8 {
+            let column: Vec<StateId> = states
+                .iter()
+                .map(|&x| {
+                    dfa.transitions
+                        .get(&(x, b))
+                        .map_or(DenseDFA::DEAD, |to| ids[to])
+                })
+                .collect();
+            let class_count = columns.len();
+            classes[b as usize] = *columns.entry(column).or_insert(class_count as u8);
+        }
+        let class_count = columns.len();
+        let mut transitions = vec![DenseDFA::DEAD; (states.len() + 1) * class_count];
+        for (&(from, b), to) in &dfa.transitions {
+            transitions[ids[&from] * class_count + classes[b as usize] as usize] = ids[to];
+        }
+        let mut final_states = vec![None; states.len() + 1];
+        for (x, branches) in &dfa.final_states {
+            final_states[ids[x]] = branches.iter().min().cloned();
+        }
+        DenseDFA {
+            initial_state: ids[&dfa.initial_state],
+            classes,
+            class_count,
+            transitions,
+            final_states,
+            context_states: dfa
+                .context_states
+                .iter()
+                .map(|(x, branches)| (ids[x], branches.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl DenseDFA {
+    /// The dead state, reached on any byte the DFA cannot accept.
+    pub const DEAD: StateId = 0;
+
+    /// The state reached from `state` on byte `b`.
+    #[inline]
+    pub fn next(&self, state: StateId, b: u8) -> StateId {
+        self.transitions[state * self.class_count + self.classes[b as usize] as usize]
+    }
+
+    /// The lowest branch of `state` if it is final.
+    #[inline]
+    pub fn branch(&self, state: StateId) -> Option<BranchId> {
+        self.final_states[state]
+    }
+
+    /// The chars `state` has a transition on, as sorted ranges.
+    ///
+    /// Every char is looked up in the table, but sequences of bytes leading to the dead state
+    /// are pruned as soon as they do.
+    pub fn accepted_chars(&self, state: StateId) -> Vec<(char, char)> {
+        use utf8_ranges::{Utf8Range, Utf8Sequences};
+
+        fn walk(
+            dfa: &DenseDFA,
+            state: StateId,
+            ranges: &[Utf8Range],
+            prefix: &mut Vec<u8>,
+            ret: &mut Vec<(char, char)>,
+        ) {
+            let (range, rest) = match ranges.split_first() {
+                Some(split) => split,
+                None => {
+                    let ch = std::str::from_utf8(prefix).unwrap().chars().next().unwrap();
+                    match ret.last_mut() {
+                        Some((_, r)) if *r as u32 + 1 == ch as u32 => *r = ch,
+                        _ => ret.push((ch, ch)),
+                    }
+                    return;
+                }
+            };
+            for b in range.start..=range.end {
+                let next = dfa.next(state, b);
+                if next != DenseDFA::DEAD {
+                    prefix.push(b);
+                    walk(dfa, next, rest, prefix, ret);
+                    prefix.pop();
+                }
+            }
+        }
+
+        let mut ret = Vec::new();
+        for seq in Utf8Sequences::new('\0', char::MAX) {
+            walk(self, state, seq.as_slice(), &mut Vec::new(), &mut ret);
+        }
+        ret
+    }
+
+    /// Whether the DFA accepts the input.
+    pub fn accepts<I: IntoIterator<Item = u8>>(&self, input: I) -> bool {
+        let mut state = self.initial_state;
+        for b in input {
+            state = self.next(state, b);
+            if state == DenseDFA::DEAD {
+                return false;
+            }
+        }
+        self.branch(state).is_some()
+    }
+}
+
 /// Minimizes a vector of `u8` ot its string description
 /// For example, [1, 2, 3, 4, 5, 9, 11, 12, 13] -> "[1-5], 9, [11,13]"
 fn vec_to_string(mut vec: Vec<u8>) -> String {
@@ -5,12 +5,18 @@ Lexers.
 See the home document page for details
 */
 
-use std::iter::Peekable;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::iter;
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::automatons::{BranchId, StateId, DFA};
-use crate::span::{Location, Span};
+use crate::automatons::{BranchId, DenseDFA, StateId, DFA, LINE_END, LINE_START, NFA};
+use crate::regex::{
+    compile_regex_part, compile_regex_syntax_part, Definitions, Regex, RegexError, RegexOptions,
+};
+use crate::span::{FileId, Location, Newlines, SourceMap, Span};
 
 /// A token handler enables custom conversions from the original strings
 /// to user-defined token type enum. In this handler users can, for example:
@@ -20,6 +26,9 @@ use crate::span::{Location, Span};
 /// * Trim quotes around a string if the token is a string literal, and probably
 ///   deal with escape characters in it
 /// * etc.
+///
+/// The string is only borrowed for the call, so the token cannot borrow from it. Use
+/// `Lexer::tokens_with` or `Lexer::next_match` for tokens borrowing from the input.
 pub type TokenHandler<T> = Box<dyn Fn(&str, Span) -> T>;
 
 /// The lexer type that parses some string and returns converted tokens of type `T`
@@ -28,14 +37,49 @@ pub type TokenHandler<T> = Box<dyn Fn(&str, Span) -> T>;
 /// the context is stored in the `LexerState<T>` class.
 pub struct Lexer<T> {
     pub dfa: DFA,
+    /// `dfa` compiled into a table, which the lexer runs on.
+    pub table: DenseDFA,
     pub discarded_branch: BranchId,
     pub handlers: FxHashMap<BranchId, TokenHandler<T>>,
+    /// Trailing contexts of the rules that have one.
+    pub contexts: FxHashMap<BranchId, TrailingContext>,
+}
+
+/// Trailing context of a rule (`r/s` in flex), which must follow a token of the rule but is not
+/// part of it.
+pub enum TrailingContext {
+    /// The context always has this many chars, so the token ends that many chars before the
+    /// end of the match.
+    Fixed(usize),
+    /// The DFA of a context of variable length. The token ends at the last position where the
+    /// regex of the rule can end and the rest of the match is accepted by this DFA.
+    Variable(DFA),
+}
+
+impl From<NFA> for TrailingContext {
+    /// Constructs the trailing context matching the NFA.
+    fn from(nfa: NFA) -> Self {
+        let dfa = DFA::from(nfa).minimize();
+        match dfa.fixed_length() {
+            Some(len) => TrailingContext::Fixed(len),
+            None => TrailingContext::Variable(dfa),
+        }
+    }
 }
 
 /// Holds the context
+///
+/// Characters are pulled from `chars` on demand and buffered in `lookahead` until they are
+/// consumed by `next`, so the lexer can scan past the end of a token and give the extra
+/// characters back.
 pub struct LexerState<T: Iterator<Item = char>> {
-    pub chars: Peekable<T>,
+    pub chars: T,
+    pub lookahead: VecDeque<char>,
     pub location: Location,
+    /// The file of the spans of tokens.
+    pub file: FileId,
+    /// The line breaks of the input.
+    pub newlines: Newlines,
 }
 
 /// LexerState can be constructed from any character iterator
@@ -45,8 +89,11 @@ where
 {
     fn from(s: T) -> Self {
         LexerState {
-            chars: s.peekable(),
-            location: Location::new(1, 0),
+            chars: s,
+            lookahead: VecDeque::new(),
+            location: Location::start(),
+            file: FileId::default(),
+            newlines: Newlines::default(),
         }
     }
 }
@@ -57,142 +104,1121 @@ where
 {
     /// Whether we have reached EOF.
     pub fn eof(&mut self) -> bool {
-        self.chars.peek().is_none()
+        self.peek_nth(0).is_none()
     }
 
     /// Current character the state holds, panics with message "End of file" if already EOF.
     pub fn current(&mut self) -> &char {
-        self.chars.peek().expect("End of file")
+        self.peek_nth(0).expect("End of file");
+        &self.lookahead[0]
+    }
+
+    /// Looks at the `n`-th character after the current one without consuming anything,
+    /// returns `None` if the input ends before that.
+    pub fn peek_nth(&mut self, n: usize) -> Option<char> {
+        while self.lookahead.len() <= n {
+            self.lookahead.push_back(self.chars.next()?);
+        }
+        Some(self.lookahead[n])
+    }
+
+    /// Whether the current character is the first one of a line.
+    pub fn at_line_start(&self) -> bool {
+        self.location.col == 0
+    }
+
+    /// Lexes the input as file `file`, whose id is then in the spans of tokens.
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// Breaks lines of the input where `newlines` says so, instead of only at `\n`.
+    pub fn with_newlines(mut self, newlines: Newlines) -> Self {
+        self.newlines = newlines;
+        self
+    }
+
+    /// Whether the input is at the end of a line, i.e. a line break or EOF follows the first
+    /// `n` characters.
+    fn at_line_end(&mut self, n: usize) -> bool {
+        let newlines = self.newlines;
+        self.peek_nth(n).is_none_or(|ch| newlines.is_line_break(ch))
     }
 
     /// Move on to the next character
     pub fn next(&mut self) {
-        if !self.eof() {
-            let ch = self.chars.next().unwrap();
-            if ch == '\n' {
-                self.location.line += 1;
-                self.location.col = 0;
-            } else {
-                self.location.col += 1;
-            }
+        self.bump();
+    }
+
+    /// Consumes the current character and returns it, `None` at EOF.
+    fn bump(&mut self) -> Option<char> {
+        self.peek_nth(0)?;
+        let ch = self.lookahead.pop_front().unwrap();
+        // Only a `\r` needs the next char to tell whether it breaks the line
+        let next = if ch == '\r' { self.peek_nth(0) } else { None };
+        self.location.advance(ch, next, self.newlines);
+        Some(ch)
+    }
+}
+
+/// Holds the context of lexing an input that is entirely in memory, see
+/// `Lexer::next_token_slice`.
+///
+/// Unlike `LexerState`, the input is never copied, tokens are slices of it.
+pub struct SliceState<'s> {
+    pub input: &'s [u8],
+    /// Location of the current character, `location.offset` being its offset in `input`.
+    pub location: Location,
+    /// The file of the spans of tokens.
+    pub file: FileId,
+    /// The line breaks of the input.
+    pub newlines: Newlines,
+}
+
+impl<'s> From<&'s str> for SliceState<'s> {
+    fn from(s: &'s str) -> Self {
+        SliceState::from(s.as_bytes())
+    }
+}
+
+/// The input must be UTF-8, the lexer stops with an error at the first invalid byte sequence.
+impl<'s> From<&'s [u8]> for SliceState<'s> {
+    fn from(input: &'s [u8]) -> Self {
+        SliceState {
+            input,
+            location: Location::start(),
+            file: FileId::default(),
+            newlines: Newlines::default(),
         }
     }
 }
 
+impl<'s> SliceState<'s> {
+    /// Lexes the text of `file` in the source map, with the line breaks of the file.
+    pub fn in_source(map: &'s SourceMap, file: FileId) -> Self {
+        let source = map.file(file);
+        SliceState::from(source.src.as_str())
+            .with_file(file)
+            .with_newlines(source.newlines)
+    }
+
+    /// Lexes the input as file `file`, whose id is then in the spans of tokens.
+    pub fn with_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// Breaks lines of the input where `newlines` says so, instead of only at `\n`.
+    pub fn with_newlines(mut self, newlines: Newlines) -> Self {
+        self.newlines = newlines;
+        self
+    }
+
+    /// Whether we have reached EOF.
+    pub fn eof(&self) -> bool {
+        self.location.offset >= self.input.len()
+    }
+
+    /// The input not consumed yet.
+    pub fn rest(&self) -> &'s [u8] {
+        &self.input[self.location.offset..]
+    }
+
+    /// Whether the current character is the first one of a line.
+    pub fn at_line_start(&self) -> bool {
+        self.location.col == 0
+    }
+
+    /// The chars of the rest of the input, up to the first invalid byte sequence.
+    fn chars(&self) -> impl Iterator<Item = char> + 's {
+        let mut rest = self.rest();
+        iter::from_fn(move || {
+            let ch = first_char(rest)?;
+            rest = &rest[ch.len_utf8()..];
+            Some(ch)
+        })
+    }
+
+    /// Consumes the next `len` bytes, which must be valid UTF-8, and returns them with their
+    /// span.
+    fn consume(&mut self, len: usize) -> Result<(&'s str, Span), Failure> {
+        let text = std::str::from_utf8(&self.rest()[..len]).map_err(|e| {
+            let valid = &self.rest()[..e.valid_up_to()];
+            Failure::InvalidUtf8 {
+                len: std::str::from_utf8(valid).unwrap().chars().count(),
+            }
+        })?;
+        let from = self.location;
+        let next = if text.ends_with('\r') {
+            first_char(&self.rest()[len..])
+        } else {
+            None
+        };
+        let to = self.location.advance_str(text, next, self.newlines);
+        Ok((text, Span::new(self.file, from, to)))
+    }
+}
+
+/// Number of continuation bytes following `lead`, the first byte of a UTF-8 char. Bytes that
+/// cannot start a char count as chars of their own, which the DFA never accepts.
+fn continuation_len(lead: u8) -> usize {
+    match lead {
+        0xc0..=0xdf => 1,
+        0xe0..=0xef => 2,
+        0xf0..=0xf7 => 3,
+        _ => 0,
+    }
+}
+
+/// Whether `bytes`, the input after some char, are empty or start with a line break.
+fn at_line_end(bytes: &[u8], newlines: Newlines) -> bool {
+    match bytes.first() {
+        None => true,
+        Some(&b) if b < 0x80 => newlines.is_line_break(b as char),
+        Some(_) => first_char(bytes).is_some_and(|ch| newlines.is_line_break(ch)),
+    }
+}
+
+/// Whether the DFA of a trailing context accepts `rest`, the end of a match, which can also be
+/// followed by `LINE_END` for contexts ending with `$` if the match is `at_end` of a line.
+fn accepts_context<I>(dfa: &DFA, rest: I, at_end: bool) -> bool
+where
+    I: Iterator<Item = u8> + Clone,
+{
+    dfa.accepts(rest.clone()) || at_end && dfa.accepts(rest.chain(iter::once(LINE_END)))
+}
+
+/// The char `bytes` start with, if they start with valid UTF-8.
+fn first_char(bytes: &[u8]) -> Option<char> {
+    let len = match *bytes.first()? {
+        b if b < 0x80 => 1,
+        b if b < 0xe0 => 2,
+        b if b < 0xf0 => 3,
+        _ => 4,
+    };
+    let prefix = bytes.get(..len)?;
+    std::str::from_utf8(prefix).ok()?.chars().next()
+}
+
+/// Why no token could be matched, `len` being the number of chars read from the start of the
+/// token.
+enum Failure {
+    /// No rule matches, the DFA being in `state` after `len` chars and not accepting the next
+    /// one.
+    NoMatch { len: usize, state: StateId },
+    /// The token of a rule with trailing context would be empty, its match being `len` chars.
+    EmptyToken { len: usize },
+    /// The input is not valid UTF-8 after `len` chars.
+    InvalidUtf8 { len: usize },
+}
+
 impl<T> Lexer<T> {
-    pub fn next_token<I>(&self, state: &mut LexerState<I>) -> Result<T, &'static str>
+    /// Reports for every rule whether it can ever produce a token and which rules it overlaps
+    /// with, see `LexerBuilder::analyze` to check rules before building the lexer.
+    pub fn analyze(&self) -> Vec<RuleReport> {
+        let mut branches: BTreeSet<BranchId> = self.handlers.keys().cloned().collect();
+        branches.extend(self.dfa.final_states.values().flatten());
+        if self.discarded_branch != BranchId::MAX {
+            branches.insert(self.discarded_branch);
+        }
+        analyze(&self.dfa, branches)
+    }
+
+    /// Matches the longest prefix of the remaining input accepted by any rule and converts it
+    /// with the handler of that rule. Discarded tokens are skipped.
+    ///
+    /// The DFA is run as far as transitions exist, remembering the last position at which it
+    /// was in a final state; characters read after that position are given back to `state`.
+    pub fn next_token<I>(&self, state: &mut LexerState<I>) -> Result<T, LexError>
     where
         I: Iterator<Item = char>,
     {
-        if state.eof() {
-            return Err("End of file");
+        loop {
+            if state.eof() {
+                return Err(LexError::end_of_file(
+                    state.file,
+                    state.location,
+                    state.newlines,
+                ));
+            }
+            let (branch, token, span) = self.match_chars(state)?;
+            if let Some(handler) = self.handlers.get(&branch) {
+                return Ok(handler(&token, span));
+            }
+            // Discarded tokens are skipped
         }
-        // Starting from the initial state of the DFA
-        let mut dfa_state: StateId = self.dfa.initial_state;
-        // Record we start matching the token
-        let from = state.location;
-        let mut to = from;
-        // If matching is aborted, is the token currently accepted by any of the rules?
-        let mut accepted = false;
-        // Is the current char accepted by the DFA (transitions exists)
-        let mut ch_accepted = true;
-        // Matched token so far
-        let mut token = String::new();
+    }
+
+    /// Matches and consumes the next token of `state`, discarded or not, returning the branch
+    /// of the rule it matches. There must be some input left.
+    fn match_chars<I>(
+        &self,
+        state: &mut LexerState<I>,
+    ) -> Result<(BranchId, String, Span), LexError>
+    where
+        I: Iterator<Item = char>,
+    {
+        // Starting from the initial state of the DFA, past the start of line if we are at one
+        let table = &self.table;
+        let mut dfa_state: StateId = table.initial_state;
+        if state.at_line_start() {
+            let next = table.next(dfa_state, LINE_START);
+            if next != DenseDFA::DEAD {
+                dfa_state = next;
+            }
+        }
+        // States reached after every character, used to find the end of a token followed by
+        // trailing context, only kept if some rule has trailing context
+        let track = !self.contexts.is_empty();
+        let mut path = Vec::new();
+        if track {
+            path.push(dfa_state);
+        }
+        // Number of characters looked at so far
+        let mut len = 0;
+        // The branch of the last match and the number of characters consumed to reach it
+        let mut last_accepted: Option<(BranchId, usize)> = None;
+        // The state after the last character the DFA accepts
+        let mut live = dfa_state;
         // Match until no transition of a certain character can be found in the DFA
-        while !state.eof() && ch_accepted {
-            let ch = *state.current();
+        'scan: while let Some(ch) = state.peek_nth(len) {
             let mut buf = [0u8; 4];
-            let mut tmp_state = dfa_state;
-            ch_accepted = true;
             // Encode a char to utf8 code points
             for &b in ch.encode_utf8(&mut buf).as_bytes() {
-                // Try state transition from `tmp_state` with input `ch`
-                if let Some(&next) = self.dfa.transitions.get(&(tmp_state, b)) {
-                    tmp_state = next;
-                } else {
-                    // The DFA cannot accept this character
-                    ch_accepted = false;
-                    break;
-                }
-            }
-            if ch_accepted {
-                // Update state
-                dfa_state = tmp_state;
-                // Are we accepted now?
-                accepted = self.dfa.final_states.contains_key(&dfa_state);
-                to = state.location.clone();
-                token.push(ch);
-                state.next();
-            }
-        }
-        if !accepted {
-            Err("Empty input or input cannot be accepted by DFA")
-        } else {
-            let branch = self.dfa.final_states[&dfa_state].iter().min().unwrap();
-            if let Some(handler) = self.handlers.get(branch) {
-                Ok(handler(&token, Span::new(from, to)))
+                // Try state transition from `dfa_state` with input `b`
+                dfa_state = table.next(dfa_state, b);
+            }
+            // The DFA cannot accept this character
+            if dfa_state == DenseDFA::DEAD {
+                break 'scan;
+            }
+            len += 1;
+            live = dfa_state;
+            if track {
+                path.push(dfa_state);
+            }
+            // Rules ending with `$` also match if a line break or the end of input follows
+            let line_end = table
+                .branch(table.next(dfa_state, LINE_END))
+                .filter(|_| state.at_line_end(len));
+            let branch = table.branch(dfa_state).into_iter().chain(line_end).min();
+            if let Some(branch) = branch {
+                last_accepted = Some((branch, len));
+            }
+        }
+        let (branch, len) = match last_accepted {
+            Some(accepted) => Ok(accepted),
+            None => Err(Failure::NoMatch { len, state: live }),
+        }
+        .and_then(|(branch, len)| match self.contexts.get(&branch) {
+            Some(context) => {
+                let at_end = state.at_line_end(len);
+                let lookahead = &state.lookahead;
+                let token_len = self.token_len(&path, len, branch, context, |dfa, end| {
+                    let rest: String = lookahead.range(end..len).collect();
+                    accepts_context(dfa, rest.bytes(), at_end)
+                })?;
+                Ok((branch, token_len))
+            }
+            None => Ok((branch, len)),
+        })
+        .map_err(|failure| {
+            let chars = state.lookahead.iter().cloned();
+            self.error(failure, state.file, state.location, state.newlines, chars)
+        })?;
+        // Record we start matching the token
+        let from = state.location;
+        let mut to = from;
+        // Consume exactly the accepted prefix, the rest stays in the lookahead buffer
+        let mut token = String::with_capacity(len);
+        for _ in 0..len {
+            to = state.location;
+            token.push(state.bump().expect("Chars read by the lexer"));
+        }
+        Ok((branch, token, Span::new(state.file, from, to)))
+    }
+
+    /// Same as `next_token`, but for input in memory: the DFA runs on the bytes of the input
+    /// directly and handlers get a slice of it instead of a copy.
+    pub fn next_token_slice(&self, state: &mut SliceState) -> Result<T, LexError> {
+        let (branch, token, span) = self.next_match(state)?;
+        // Discarded tokens are skipped by `next_match`
+        Ok(self.handlers[&branch](token, span))
+    }
+
+    /// Matches the next token that is not discarded like `next_token_slice`, but returns the
+    /// branch of the rule it matches and the token itself instead of converting it, so the
+    /// token can outlive the lexer and borrow from the input.
+    pub fn next_match<'s>(
+        &self,
+        state: &mut SliceState<'s>,
+    ) -> Result<(BranchId, &'s str, Span), LexError> {
+        self.try_next_match(state).unwrap_or_else(|| {
+            Err(LexError::end_of_file(
+                state.file,
+                state.location,
+                state.newlines,
+            ))
+        })
+    }
+
+    /// Same as `next_match`, but returns `None` at EOF, even if discarded tokens come before it.
+    fn try_next_match<'s>(
+        &self,
+        state: &mut SliceState<'s>,
+    ) -> Option<Result<(BranchId, &'s str, Span), LexError>> {
+        while !state.eof() {
+            let failure = match self.scan(state) {
+                Ok((branch, len)) => match state.consume(len) {
+                    Ok((token, span)) if self.handlers.contains_key(&branch) => {
+                        return Some(Ok((branch, token, span)));
+                    }
+                    Ok(_) => continue,
+                    Err(failure) => failure,
+                },
+                Err(failure) => failure,
+            };
+            let chars = state.chars();
+            let error = self.error(failure, state.file, state.location, state.newlines, chars);
+            return Some(Err(error));
+        }
+        None
+    }
+
+    /// Converts the tokens of the input one by one, see `Tokens`.
+    pub fn tokens<'s, S>(&self, input: S) -> Tokens<'_, 's, T>
+    where
+        S: Into<SliceState<'s>>,
+    {
+        self.tokens_with(input, move |branch, token, span| {
+            self.handlers[&branch](token, span)
+        })
+    }
+
+    /// Same as `tokens`, but converts the tokens with `convert` instead of the handlers of the
+    /// rules. The handlers only get the token for the duration of the call, while `convert` gets
+    /// it for as long as the input lives, so the tokens it returns can borrow from the input.
+    ///
+    /// `convert` is given the branch of the rule each token matches, discarded tokens are still
+    /// skipped.
+    pub fn tokens_with<'l, 's, S, U, F>(&'l self, input: S, convert: F) -> Tokens<'l, 's, T, U>
+    where
+        S: Into<SliceState<'s>>,
+        F: FnMut(BranchId, &'s str, Span) -> U + 'l,
+    {
+        Tokens {
+            lexer: self,
+            state: input.into(),
+            convert: Box::new(convert),
+            lookahead: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// The branch and length in bytes of the longest prefix of the rest of `state` accepted by
+    /// a rule, the byte counterpart of the matching in `next_token`. There must be some input
+    /// left.
+    fn scan(&self, state: &SliceState) -> Result<(BranchId, usize), Failure> {
+        let input = state.rest();
+        let table = &self.table;
+        let mut dfa_state: StateId = table.initial_state;
+        if state.at_line_start() {
+            let next = table.next(dfa_state, LINE_START);
+            if next != DenseDFA::DEAD {
+                dfa_state = next;
+            }
+        }
+        // States reached after every character and where the characters end, only kept if
+        // some rule has trailing context
+        let track = !self.contexts.is_empty();
+        let mut path = Vec::new();
+        let mut ends = Vec::new();
+        if track {
+            path.push(dfa_state);
+            ends.push(0);
+        }
+        // Number of characters looked at so far
+        let mut len = 0;
+        // The branch of the last match with the number of characters and bytes to reach it
+        let mut last_accepted: Option<(BranchId, usize, usize)> = None;
+        // The state after the last character the DFA accepts and where the character ends
+        let mut live = (dfa_state, 0);
+        let mut pos = 0;
+        // Continuation bytes left before the current char ends
+        let mut pending = 0;
+        while pos < input.len() {
+            // The bytes standing for anchors never occur in UTF-8, so they match nothing
+            if input[pos] >= LINE_END {
+                break;
+            }
+            pending = match pending {
+                0 => continuation_len(input[pos]),
+                n => n - 1,
+            };
+            dfa_state = table.next(dfa_state, input[pos]);
+            pos += 1;
+            if dfa_state == DenseDFA::DEAD {
+                break;
+            }
+            // Only check for matches at the end of a character
+            if pending > 0 {
+                continue;
+            }
+            len += 1;
+            live = (dfa_state, pos);
+            if track {
+                path.push(dfa_state);
+                ends.push(pos);
+            }
+            let line_end = if at_line_end(&input[pos..], state.newlines) {
+                table.branch(table.next(dfa_state, LINE_END))
             } else {
-                self.next_token(state) // If it is discarded?
+                None
+            };
+            let branch = table.branch(dfa_state).into_iter().chain(line_end).min();
+            if let Some(branch) = branch {
+                last_accepted = Some((branch, len, pos));
+            }
+        }
+        let (branch, len, bytes) = match last_accepted {
+            Some(accepted) => accepted,
+            None if first_char(&input[live.1..]).is_none() && live.1 < input.len() => {
+                return Err(Failure::InvalidUtf8 { len })
+            }
+            None => return Err(Failure::NoMatch { len, state: live.0 }),
+        };
+        match self.contexts.get(&branch) {
+            Some(context) => {
+                let at_end = at_line_end(&input[bytes..], state.newlines);
+                let token_len = self.token_len(&path, len, branch, context, |dfa, end| {
+                    accepts_context(dfa, input[ends[end]..bytes].iter().cloned(), at_end)
+                })?;
+                Ok((branch, ends[token_len]))
             }
+            None => Ok((branch, bytes)),
+        }
+    }
+
+    /// Length of the token in a match of `len` chars of a rule with trailing context,
+    /// `path` being the DFA states reached after every char of the match.
+    ///
+    /// `accepts_rest(dfa, end)` tells whether `dfa` accepts the chars of the match from `end` on,
+    /// which are none if `end` is `len`.
+    fn token_len<F>(
+        &self,
+        path: &[StateId],
+        len: usize,
+        branch: BranchId,
+        context: &TrailingContext,
+        accepts_rest: F,
+    ) -> Result<usize, Failure>
+    where
+        F: Fn(&DFA, usize) -> bool,
+    {
+        let token_len = match context {
+            TrailingContext::Fixed(context_len) => Some(len - context_len),
+            TrailingContext::Variable(dfa) => (1..=len).rev().find(|&end| {
+                let ends_here = self
+                    .table
+                    .context_states
+                    .get(&path[end])
+                    .is_some_and(|branches| branches.contains(&branch));
+                ends_here && accepts_rest(dfa, end)
+            }),
+        };
+        // A token must not be empty, or the lexer would not make progress
+        token_len
+            .filter(|&len| len > 0)
+            .ok_or(Failure::EmptyToken { len })
+    }
+
+    /// The error of a token starting at `from` that failed, `chars` being the input from there
+    /// on, which must hold the chars read by the lexer.
+    #[cold]
+    fn error<I>(
+        &self,
+        failure: Failure,
+        file: FileId,
+        from: Location,
+        newlines: Newlines,
+        chars: I,
+    ) -> LexError
+    where
+        I: Iterator<Item = char>,
+    {
+        let len = match failure {
+            Failure::NoMatch { len, .. }
+            | Failure::EmptyToken { len }
+            | Failure::InvalidUtf8 { len } => len,
+        };
+        let mut chars = chars.peekable();
+        let mut location = from;
+        let mut to = from;
+        for _ in 0..len {
+            let ch = chars.next().expect("Chars read by the lexer");
+            to = location;
+            location.advance(ch, chars.peek().cloned(), newlines);
+        }
+        // The span ends with the char that broke the match, if any
+        let found = chars.next();
+        if found.is_some() {
+            to = location;
+        }
+        let kind = match failure {
+            Failure::NoMatch { state, .. } => LexErrorKind::NoMatch {
+                found,
+                expected: self.table.accepted_chars(state),
+            },
+            Failure::EmptyToken { .. } => LexErrorKind::EmptyToken,
+            Failure::InvalidUtf8 { .. } => LexErrorKind::InvalidUtf8,
+        };
+        LexError {
+            kind,
+            span: Span::new(file, from, to),
+            newlines,
         }
     }
 }
 
-/// Macro that helps define a lexer
-/// The usage is shown in README
-#[macro_export]
-macro_rules! define_lexer {
-    ($token_type:ty = $($re:expr => $handler:expr),+) => {{
-        use particle::automatons::{NFA, DFA, BranchId};
-        use particle::regex::compile_regex;
-        use particle::lexer::{TokenHandler, Lexer};
-        use rustc_hash::FxHashMap;
-
-        let mut nfa = NFA::new();
-        let mut next_branch:BranchId = 0;
-        let mut handlers: FxHashMap<BranchId, TokenHandler<$token_type>> = FxHashMap::default();
-        $(
-            next_branch += 1;
-            nfa = nfa | {
-                let mut rule = compile_regex($re).unwrap();
-                rule.set_branch(next_branch);
-                handlers.insert(next_branch, Box::new($handler));
-                rule
-            };
-        )*
-        Lexer {
-            dfa: DFA::from(nfa),
-            discarded_branch: 32,
-            handlers,
+/// Kinds of errors of lexing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexErrorKind {
+    /// The input ended, returned when asking for a token at EOF.
+    EndOfFile,
+    /// No rule matches the input, `found` being the char the lexer cannot go on with (`None`
+    /// at EOF) and `expected` the chars it could have gone on with, as sorted ranges.
+    NoMatch {
+        found: Option<char>,
+        expected: Vec<(char, char)>,
+    },
+    /// The token of a rule with trailing context would be empty, so the lexer cannot make
+    /// progress.
+    EmptyToken,
+    /// The input is not valid UTF-8.
+    InvalidUtf8,
+}
+
+/// Error raised when the input cannot be lexed.
+///
+/// `render` prints the error with the offending part of the input underlined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    /// The text read for the token that failed, up to the char that broke it if any.
+    pub span: Span,
+    /// The line breaks of the input, which `render` splits its lines with.
+    pub newlines: Newlines,
+}
+
+impl LexError {
+    /// The error of asking for a token at `location`, the end of the input.
+    fn end_of_file(file: FileId, location: Location, newlines: Newlines) -> Self {
+        LexError {
+            kind: LexErrorKind::EndOfFile,
+            span: Span::new(file, location, location),
+            newlines,
         }
-    }};
-    ($token_type:ty = discard $dis:expr, $($re:expr => $handler:expr),+) => {{
-        use particle::automatons::{NFA, DFA, BranchId};
-        use particle::regex::compile_regex;
-        use particle::lexer::{TokenHandler, Lexer};
-        use rustc_hash::FxHashMap;
-
-        let mut nfa = NFA::new();
-        let mut next_branch:BranchId = 0;
-        let mut handlers: FxHashMap<BranchId, TokenHandler<$token_type>> = FxHashMap::default();
-        nfa = nfa | {
-            let mut discarded = compile_regex($dis).unwrap();
-            discarded.set_branch(0);
-            discarded
+    }
+
+    /// Prints the error followed by the line of `src` it starts at, the source text of the
+    /// span, with the span underlined. Lines are split with the line breaks of the lexer state
+    /// the error comes from.
+    pub fn render(&self, src: &str) -> String {
+        let Span { from, to, .. } = self.span;
+        // The line of the error, from its start to the first line break
+        let start = src[..from.offset]
+            .char_indices()
+            .rev()
+            .take(from.col)
+            .last()
+            .map_or(from.offset, |(i, _)| i);
+        let line = src[start..]
+            .split(|ch| self.newlines.is_line_break(ch))
+            .next()
+            .unwrap_or("");
+        let width = if to.line == from.line {
+            to.col + 1 - from.col
+        } else {
+            line.chars().count().saturating_sub(from.col)
         };
-        $(
-            next_branch += 1;
-            nfa = nfa | {
-                let mut rule = compile_regex($re).unwrap();
-                rule.set_branch(next_branch);
-                handlers.insert(next_branch, Box::new($handler));
-                rule
-            };
-        )*
+        // Tabs are kept in the indentation so that it lines up with the text
+        let indent: String = line
+            .chars()
+            .take(from.col)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "{}\n    {}\n    {}{}",
+            self,
+            line,
+            indent,
+            "^".repeat(cmp::max(width, 1))
+        )
+    }
+}
+
+impl Display for LexErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let class = |ranges: &Vec<(char, char)>| Regex::Class {
+            ranges: ranges.clone(),
+            negated: false,
+        };
+        match self {
+            LexErrorKind::EndOfFile => write!(f, "end of file"),
+            LexErrorKind::NoMatch {
+                found: Some(ch),
+                expected,
+            } if expected.is_empty() => write!(f, "unexpected {:?}", ch),
+            LexErrorKind::NoMatch {
+                found: Some(ch),
+                expected,
+            } => write!(f, "expecting {}, found {:?}", class(expected), ch),
+            LexErrorKind::NoMatch {
+                found: None,
+                expected,
+            } => write!(f, "expecting {}, found end of file", class(expected)),
+            LexErrorKind::EmptyToken => write!(f, "token with trailing context cannot be empty"),
+            LexErrorKind::InvalidUtf8 => write!(f, "input is not valid UTF-8"),
+        }
+    }
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.kind,
+            self.span.from.line,
+            self.span.from.col + 1
+        )
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// Iterator over the tokens of an input, converted into `U`, see `Lexer::tokens` and
+/// `Lexer::tokens_with`.
+///
+/// The iterator ends at EOF, or after the first error since the lexer cannot make progress past
+/// it. Tokens can be looked at ahead of time with `peek` and `peek_nth`.
+pub struct Tokens<'l, 's, T, U = T> {
+    lexer: &'l Lexer<T>,
+    state: SliceState<'s>,
+    /// Converts a match, given the branch of its rule, into the token returned.
+    convert: Box<dyn FnMut(BranchId, &'s str, Span) -> U + 'l>,
+    /// Tokens lexed but not returned yet.
+    lookahead: VecDeque<Result<U, LexError>>,
+    /// Whether EOF or an error has been reached.
+    done: bool,
+}
+
+impl<'l, 's, T, U> Tokens<'l, 's, T, U> {
+    /// The state of the input, which is past the tokens in the lookahead buffer.
+    pub fn state(&self) -> &SliceState<'s> {
+        &self.state
+    }
+
+    /// Looks at the next token without consuming it.
+    pub fn peek(&mut self) -> Option<&Result<U, LexError>> {
+        self.peek_nth(0)
+    }
+
+    /// Looks at the `n`-th token after the next one without consuming anything, returns `None`
+    /// if the tokens end before that.
+    pub fn peek_nth(&mut self, n: usize) -> Option<&Result<U, LexError>> {
+        while self.lookahead.len() <= n {
+            let token = self.lex()?;
+            self.lookahead.push_back(token);
+        }
+        self.lookahead.get(n)
+    }
+
+    /// Lexes the next token from the input.
+    fn lex(&mut self) -> Option<Result<U, LexError>> {
+        if self.done {
+            return None;
+        }
+        let token = match self.lexer.try_next_match(&mut self.state) {
+            Some(Ok((branch, token, span))) => Ok((self.convert)(branch, token, span)),
+            Some(Err(error)) => Err(error),
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+        self.done = token.is_err();
+        Some(token)
+    }
+}
+
+impl<'l, 's, T, U> Iterator for Tokens<'l, 's, T, U> {
+    type Item = Result<U, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lookahead.pop_front().or_else(|| self.lex())
+    }
+}
+
+/// How a rule of a lexer interacts with the other rules, see `Lexer::analyze`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleReport {
+    pub branch: BranchId,
+    /// Whether some token is produced by the rule. Otherwise every token it matches is matched by
+    /// an earlier rule too, or it matches nothing at all.
+    pub can_win: bool,
+    /// The other rules matching some of the tokens this rule matches, by branch id.
+    pub overlaps: Vec<Overlap>,
+}
+
+/// Another rule matching some of the tokens a rule matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub branch: BranchId,
+    /// A shortest token matched by both rules. Anchors, which only concern the chars around the
+    /// token, are left out.
+    pub example: String,
+}
+
+impl Display for RuleReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "rule {}", self.branch)?;
+        let mut sep = " ";
+        if !self.can_win {
+            write!(f, " is shadowed")?;
+            sep = ", ";
+        }
+        for overlap in &self.overlaps {
+            write!(
+                f,
+                "{}overlaps with rule {} on {:?}",
+                sep, overlap.branch, overlap.example
+            )?;
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
+
+/// Reports how every rule in `branches` interacts with the others in the DFA of a lexer.
+fn analyze<B>(dfa: &DFA, branches: B) -> Vec<RuleReport>
+where
+    B: IntoIterator<Item = BranchId>,
+{
+    let inputs = dfa.shortest_inputs();
+    // The states a token can end in. The ones only reached by `$` are left out: the lexer only
+    // looks at them together with the state before the line end.
+    let mut ends: FxHashSet<StateId> = dfa
+        .transitions
+        .iter()
+        .filter(|&(&(_, b), _)| b != LINE_END)
+        .map(|(_, &to)| to)
+        .collect();
+    ends.insert(dfa.initial_state);
+    // The rules matching a token ending in each state, away from a line end and at one, ordered
+    // by the shortest inputs of the states, so that the first set found with a pair of branches
+    // gives a shortest example for the pair
+    let mut finals: Vec<(&Vec<u8>, FxHashSet<BranchId>)> = Vec::new();
+    for state in ends {
+        let input = match inputs.get(&state) {
+            Some(input) => input,
+            None => continue,
+        };
+        let here = dfa.final_states.get(&state);
+        if let Some(branches) = here {
+            finals.push((input, branches.clone()));
+        }
+        let line_end = dfa.transitions.get(&(state, LINE_END));
+        if let Some(branches) = line_end.and_then(|x| dfa.final_states.get(x)) {
+            let all = here.into_iter().flatten().chain(branches).cloned();
+            finals.push((input, all.collect()));
+        }
+    }
+    finals.sort_by_key(|&(input, _)| (input.len(), input));
+    let mut winners: FxHashSet<BranchId> = FxHashSet::default();
+    let mut examples: BTreeMap<(BranchId, BranchId), &Vec<u8>> = BTreeMap::new();
+    for (input, branches) in finals {
+        winners.extend(branches.iter().min());
+        for &a in &branches {
+            for &b in branches.iter().filter(|&&b| b != a) {
+                examples.entry((a, b)).or_insert(input);
+            }
+        }
+    }
+    let token = |input: &[u8]| {
+        let bytes: Vec<u8> = input
+            .iter()
+            .cloned()
+            .filter(|&b| b != LINE_START && b != LINE_END)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    branches
+        .into_iter()
+        .map(|branch| RuleReport {
+            branch,
+            can_win: winners.contains(&branch),
+            overlaps: examples
+                .range((branch, 0)..=(branch, BranchId::MAX))
+                .map(|(&(_, other), input)| Overlap {
+                    branch: other,
+                    example: token(input),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// The front end compiling the regexes of lexer rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// The regex syntax of this crate, see `compile_regex_in`. This is the default.
+    Native,
+    /// The syntax of regex-syntax (the one of the `regex` crate), see `compile_regex_syntax`.
+    /// Definitions cannot be referred to in it.
+    RegexSyntax,
+}
+
+/// Builds a `Lexer` out of rules added one by one, `define_lexer!` expands to calls to this.
+///
+/// Every rule is a regex whose matches are either converted to tokens by a handler or discarded.
+/// When several rules match the same longest prefix, the rule added first wins.
+pub struct LexerBuilder<T> {
+    nfa: NFA,
+    next_branch: BranchId,
+    discarded_branch: BranchId,
+    handlers: FxHashMap<BranchId, TokenHandler<T>>,
+    contexts: FxHashMap<BranchId, TrailingContext>,
+    definitions: Definitions,
+    syntax: Syntax,
+}
+
+impl<T> Default for LexerBuilder<T> {
+    fn default() -> Self {
+        LexerBuilder::new()
+    }
+}
+
+impl<T> LexerBuilder<T> {
+    /// Constructs a builder without any rule.
+    pub fn new() -> Self {
+        LexerBuilder {
+            nfa: NFA::new(),
+            next_branch: 0,
+            discarded_branch: BranchId::MAX,
+            handlers: FxHashMap::default(),
+            contexts: FxHashMap::default(),
+            definitions: Definitions::new(),
+            syntax: Syntax::Native,
+        }
+    }
+
+    /// Defines a named regex that rules added afterwards can refer to as `{NAME}`.
+    ///
+    /// Definitions may refer to each other regardless of the order they are defined in.
+    pub fn define(&mut self, name: &str, regex: &str) -> &mut Self {
+        self.definitions.define(name, regex);
+        self
+    }
+
+    /// Sets the front end compiling the regexes of the rules added afterwards.
+    pub fn syntax(&mut self, syntax: Syntax) -> &mut Self {
+        self.syntax = syntax;
+        self
+    }
+
+    /// The branch id the next rule added will get.
+    pub fn next_branch(&self) -> BranchId {
+        self.next_branch
+    }
+
+    /// Adds a rule whose matches are converted to tokens by `handler`,
+    /// returns the branch id of the rule.
+    pub fn rule<F>(&mut self, regex: &str, handler: F) -> Result<BranchId, RegexError>
+    where
+        F: Fn(&str, Span) -> T + 'static,
+    {
+        self.rule_with(regex, RegexOptions::default(), handler)
+    }
+
+    /// Same as `rule`, but compiles the regex with the given options.
+    pub fn rule_with<F>(
+        &mut self,
+        regex: &str,
+        options: RegexOptions,
+        handler: F,
+    ) -> Result<BranchId, RegexError>
+    where
+        F: Fn(&str, Span) -> T + 'static,
+    {
+        let branch = self.add(self.compile(regex, options)?);
+        self.handlers.insert(branch, Box::new(handler));
+        Ok(branch)
+    }
+
+    /// Adds a rule with trailing context (`regex/context` in flex): it matches `regex` only if
+    /// `context` follows, and the token covers the part matched by `regex` only. Returns the
+    /// branch id of the rule.
+    ///
+    /// If both `regex` and `context` have variable length, the token is the longest prefix of
+    /// the match accepted by `regex` such that `context` accepts the rest.
+    ///
+    /// As in flex, `regex` may start with `^` and `context` may end with `$`, but `regex` cannot
+    /// end with `$` nor `context` start with `^`.
+    pub fn rule_with_context<F>(
+        &mut self,
+        regex: &str,
+        context: &str,
+        handler: F,
+    ) -> Result<BranchId, RegexError>
+    where
+        F: Fn(&str, Span) -> T + 'static,
+    {
+        let options = RegexOptions::default();
+        let rule = self.compile_part(regex, options, true, false)?;
+        let context = self.compile_part(context, options, false, true)?;
+        let trailing = TrailingContext::from(context.clone());
+        let branch = self.add(rule.followed_by(context));
+        self.contexts.insert(branch, trailing);
+        self.handlers.insert(branch, Box::new(handler));
+        Ok(branch)
+    }
+
+    /// Adds a rule whose matches are skipped, returns the branch id of the rule.
+    pub fn discard(&mut self, regex: &str) -> Result<BranchId, RegexError> {
+        self.discard_with(regex, RegexOptions::default())
+    }
+
+    /// Same as `discard`, but compiles the regex with the given options.
+    pub fn discard_with(
+        &mut self,
+        regex: &str,
+        options: RegexOptions,
+    ) -> Result<BranchId, RegexError> {
+        let branch = self.add(self.compile(regex, options)?);
+        self.discarded_branch = branch;
+        Ok(branch)
+    }
+
+    /// Compiles the regex of a rule with the front end in use.
+    fn compile(&self, regex: &str, options: RegexOptions) -> Result<NFA, RegexError> {
+        self.compile_part(regex, options, true, true)
+    }
+
+    /// Compiles a part of the regex of a rule, anchors are only allowed at the edges of the
+    /// part that are edges of the rule (`at_start` and `at_end`).
+    fn compile_part(
+        &self,
+        regex: &str,
+        options: RegexOptions,
+        at_start: bool,
+        at_end: bool,
+    ) -> Result<NFA, RegexError> {
+        match self.syntax {
+            Syntax::Native => {
+                compile_regex_part(regex, options, &self.definitions, at_start, at_end)
+            }
+            Syntax::RegexSyntax => compile_regex_syntax_part(regex, options, at_start, at_end),
+        }
+    }
+
+    /// Merges the NFA of a rule into the lexer NFA under a new branch.
+    ///
+    /// The lexer feeds `LINE_START` to the DFA at the start of a line, so every rule accepts an
+    /// optional one before it, and rules starting with `^` match at the start of lines only.
+    fn add(&mut self, rule: NFA) -> BranchId {
+        let mut rule = NFA::from(LINE_START).optional() & rule;
+        let branch = self.next_branch;
+        self.next_branch += 1;
+        rule.set_branch(branch);
+        self.nfa = std::mem::take(&mut self.nfa) | rule;
+        branch
+    }
+
+    /// Reports for every rule added so far whether it can ever produce a token and which rules it
+    /// overlaps with, see `Lexer::analyze`.
+    pub fn analyze(&self) -> Vec<RuleReport> {
+        analyze(&DFA::from(self.nfa.clone()).minimize(), 0..self.next_branch)
+    }
+
+    /// Builds the lexer, converting the rules into a minimized DFA.
+    pub fn build(self) -> Lexer<T> {
+        let dfa = DFA::from(self.nfa).minimize();
         Lexer {
-            dfa: DFA::from(nfa),
-            discarded_branch: 0,
-            handlers,
+            table: DenseDFA::from(&dfa),
+            dfa,
+            discarded_branch: self.discarded_branch,
+            handlers: self.handlers,
+            contexts: self.contexts,
         }
+    }
+}
+
+/// Macro that helps define a lexer
+/// The usage is shown in README
+///
+/// Rules are separated by commas and each of them is one of
+///
+/// * `regex => handler`, a rule whose matches are converted to tokens by `handler`
+/// * `ignore_case regex => handler`, the same but matching case insensitively
+/// * `(regex, context) => handler`, the same but only matching if `context` follows, which is
+///   not part of the token (`regex/context` in flex)
+/// * `discard regex`, a rule whose matches are skipped
+/// * `define NAME = regex`, not a rule but a named regex that later rules refer to as `{NAME}`
+/// * `syntax Syntax`, not a rule but the front end compiling the regexes of later rules, e.g.
+///   `syntax RegexSyntax` to use the syntax of the `regex` crate (see `lexer::Syntax`)
+///
+/// The macro panics if a regex cannot be compiled.
+#[macro_export]
+macro_rules! define_lexer {
+    (@rules $builder:ident;) => {};
+    (@rules $builder:ident; syntax $syntax:ident $(, $($rest:tt)*)?) => {
+        $builder.syntax($crate::lexer::Syntax::$syntax);
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    (@rules $builder:ident; define $name:ident = $re:expr $(, $($rest:tt)*)?) => {
+        $builder.define(stringify!($name), $re);
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    (@rules $builder:ident; discard $re:expr $(, $($rest:tt)*)?) => {
+        $builder.discard($re).unwrap_or_else(|e| {
+            panic!("Cannot compile the regex of lexer rule {}: {}", $builder.next_branch(), e)
+        });
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    (@rules $builder:ident; ignore_case $re:expr => $handler:expr $(, $($rest:tt)*)?) => {
+        $builder
+            .rule_with(
+                $re,
+                $crate::regex::RegexOptions {
+                    case_insensitive: true,
+                    ..Default::default()
+                },
+                $handler,
+            )
+            .unwrap_or_else(|e| {
+                panic!("Cannot compile the regex of lexer rule {}: {}", $builder.next_branch(), e)
+            });
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    (@rules $builder:ident; ($re:expr, $ctx:expr) => $handler:expr $(, $($rest:tt)*)?) => {
+        $builder
+            .rule_with_context($re, $ctx, $handler)
+            .unwrap_or_else(|e| {
+                panic!("Cannot compile the regex of lexer rule {}: {}", $builder.next_branch(), e)
+            });
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    (@rules $builder:ident; $re:expr => $handler:expr $(, $($rest:tt)*)?) => {
+        $builder.rule($re, $handler).unwrap_or_else(|e| {
+            panic!("Cannot compile the regex of lexer rule {}: {}", $builder.next_branch(), e)
+        });
+        $crate::define_lexer!(@rules $builder; $($($rest)*)?);
+    };
+    ($token_type:ty = $($rules:tt)+) => {{
+        let mut builder = $crate::lexer::LexerBuilder::<$token_type>::new();
+        $crate::define_lexer!(@rules builder; $($rules)+);
+        builder.build()
     }};
 }
@@ -29,7 +29,6 @@ This example shows a simple lexer parsing simple expressions:
 use std::fs;
 
 use particle::define_lexer;
-use particle::lexer::LexerState;
 use particle::span::Span;
 
 #[derive(Debug)]
@@ -56,7 +55,7 @@ impl Token {
 fn main() {
     let lexer = define_lexer!(Token =
         discard r#"[ \n\r\t]+"#,
-        r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f][0-9a-f][0-9a-f][0-9a-f]))*""# =>
+        r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f]{4}))*""# =>
             |s, span| Token::from(span, TokenKind::Str(String::from(s))),
         r#"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][\+\-]?[0-9]+)?"# =>
             |s, span| Token::from(span, TokenKind::Number(s.parse().unwrap())),
@@ -85,21 +84,17 @@ fn main() {
                 "eu"
             ]
         }"#;
-    let mut state = LexerState::from(contents.chars());
-    while !state.eof() {
-        match lexer.next_token(&mut state) {
+    for token in lexer.tokens(contents) {
+        match token {
             Ok(token) => println!("{:?}", token.kind),
-            Err(msg) => {
-                eprintln!("Error!");
-                break;
-            }
+            Err(e) => eprintln!("Error! {}", e),
         }
     }
 }
 ```
 
 Running this example for some random json yields:
-```
+```text
 Punctuation("{")
 Str("\"age\"")
 Punctuation(":")
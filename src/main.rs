@@ -1,12 +1,8 @@
 //! A simple example
 
-use std::fs;
-
 use particle::define_lexer;
-use particle::lexer::LexerState;
 use particle::span::Span;
 
-#[derive(Debug)]
 enum TokenKind {
     Number(f64),
     Str(String),
@@ -15,7 +11,6 @@ enum TokenKind {
     Null,
 }
 
-#[derive(Debug)]
 struct Token {
     span: Span,
     kind: TokenKind,
@@ -30,7 +25,7 @@ impl Token {
 fn main() {
     let lexer = define_lexer!(Token =
         discard r#"[ \n\r\t]+"#,
-        r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f][0-9a-f][0-9a-f][0-9a-f]))*""# =>
+        r#""([^"\\]|\\(["\\/bfnrt]|u[0-9a-f]{4}))*""# =>
             |s, span| Token::from(span, TokenKind::Str(String::from(s))),
         r#"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][\+\-]?[0-9]+)?"# =>
             |s, span| Token::from(span, TokenKind::Number(s.parse().unwrap())),
@@ -59,14 +54,19 @@ fn main() {
                 "eu"
             ]
         }"#;
-    let mut state = LexerState::from(contents.chars());
-    while !state.eof() {
-        match lexer.next_token(&mut state) {
-            Ok(token) => println!("{:?}", token.kind),
-            Err(msg) => {
-                eprintln!("Error!");
-                break;
+    for token in lexer.tokens(contents) {
+        match token {
+            Ok(Token { span, kind }) => {
+                let line = span.from.line;
+                match kind {
+                    TokenKind::Number(n) => println!("{}: number {}", line, n),
+                    TokenKind::Str(s) => println!("{}: string {}", line, s),
+                    TokenKind::Punctuation(s) => println!("{}: punctuation {}", line, s),
+                    TokenKind::Bool(b) => println!("{}: bool {}", line, b),
+                    TokenKind::Null => println!("{}: null", line),
+                }
             }
+            Err(e) => eprintln!("Error! {}", e),
         }
     }
 }
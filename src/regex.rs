@@ -5,246 +5,1509 @@ For simplicity, only a few key features of regex are supported:
 1. Grouping `()`
 2. Bracket `[...]` and `[^...]`
 3. Branching `()`
-4. Repetition `+` and `-` (`{m, n}` are not supported)
+4. Repetition `*`, `+`, `{m}`, `{m,}` and `{m,n}` (without spaces, and with counts up to
+   `REPETITION_LIMIT`, also when multiplied through nested repetitions)
 5. Optional `?`
 6. Escape characters (the same as rust string literals)
+7. Shorthand classes `\d`, `\w`, `\s`, `\D`, `\W`, `\S` and POSIX classes like `[[:alpha:]]`
+   and `[[:^digit:]]` (all with their ASCII definitions)
+8. Unicode properties `\p{...}` and `\P{...}`: general categories (`\pL`, `\p{Letter}`),
+   scripts (`\p{Greek}`) and binary properties like `\p{XID_Start}` and `\p{XID_Continue}`
+9. Any char `.`, which matches `\n` only with the `s` flag set. Like negated classes, it covers
+   every Unicode scalar value up to U+10FFFF
+10. Inline flags `i` (case insensitive) and `s` (`.` matches `\n`), set like `(?i)`, `(?-s)`
+    or `(?is:...)`, where `(?:...)` is accepted as a plain group. `compile_regex_with` sets
+    their initial values
+11. References `{NAME}` to named regexes in `Definitions`, see `compile_regex_in`
+12. Anchors `^` (start of a line) and `$` (end of a line or the input). As they are checked by
+    the lexer around a token, `^` is only allowed at the start of a regex and `$` at its end
+13. Intersection `r&&s` (binding looser than concatenation but tighter than `|`) and complement
+    `~r` (applying to the repetition or complement following it, and matching any string of
+    chars not matched by `r`), e.g. `[a-z]+&&~(if|else)` or `(?s)<!--~(.*-->.*)-->`
+
+A regex is first parsed into a `Regex` syntax tree, which is then lowered to NFA. `parse` only
+does the first step, the tree can be inspected or printed back as a pattern.
 
 # Example
 
 ```rust
-use particle::regex::compile_regex;
+use particle::regex::{compile_regex, parse};
 let nfa = compile_regex(r#"[1-9][0-9]*(\.[0-9]+)?([eE](\+|-)?[1-9][0-9]*)?"#).unwrap();
+let regex = parse(r#"\d+(\.\d*)?"#).unwrap();
+assert_eq!(regex.to_string(), r#"[0-9]+(\.[0-9]*)?"#);
 ```
 
 */
 
 use std::cell::RefCell;
 use std::char::from_u32;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 use std::iter::Peekable;
-use std::str::Chars;
+use std::ops::Range;
+use std::str::CharIndices;
 
+use regex_syntax::ast::parse::Parser as AstParser;
+use regex_syntax::ast::{AssertionKind, Ast};
 use regex_syntax::hir::{
-    Class, Group, GroupKind, Hir, HirKind, Literal, Repetition, RepetitionKind,
+    Anchor, Class, Group, Hir, HirKind, Literal, Repetition, RepetitionKind, RepetitionRange,
 };
+use regex_syntax::hir::{ClassUnicode, ClassUnicodeRange};
+use regex_syntax::ParserBuilder;
+use rustc_hash::FxHashMap;
 
-use crate::automatons::NFA;
+use crate::automatons::{Alphabet, Counterexample, DFA, LINE_END, LINE_START, NFA};
 
-fn parse_char(it: &mut Peekable<Chars>) -> Result<char, &'static str> {
-    let ret = match it.peek() {
-        Some('\\') => match {
-            it.next();
-            it.peek()
-        } {
-            Some('\\') => Ok('\\'),
-            Some('\'') => Ok('\''),
-            Some('\"') => Ok('\"'),
-            Some('0') => Ok('\0'),
-            Some('n') => Ok('\n'),
-            Some('r') => Ok('\r'),
-            Some('t') => Ok('\t'),
-            Some('x') => {
-                let mut val = 0u8;
-                // Expecting 2 hex digits
-                for _ in 0..2 {
-                    match {
-                        it.next();
-                        it.peek()
-                    } {
-                        Some(ch) if ch.is_digit(16) => {
-                            val = (val << 4) + ch.to_digit(16).unwrap() as u8
-                        }
-                        _ => return Err("Expected digit following ascii escape sequence"),
+/// The largest count accepted in a counted repetition like `{m,n}`, which also bounds the
+/// product of the counts of nested repetitions like `(a{10}){10}`.
+///
+/// Every repetition copies the repeated NFA, so larger counts quickly blow up the number of
+/// states, these are refused instead.
+pub const REPETITION_LIMIT: usize = 1000;
+
+/// The largest char matched by `.` and negated char classes.
+const MAX_CHAR: u32 = 0x10ffff;
+
+/// The surrogate code points, which are not valid chars.
+const SURROGATES: (u32, u32) = (0xd800, 0xdfff);
+
+/// Options of `compile_regex_with`.
+///
+/// These are the initial values of the flags that can also be set inline by `(?flags)` or
+/// `(?flags:...)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RegexOptions {
+    /// Whether letters match their other cases too, using simple Unicode case folding (flag `i`).
+    pub case_insensitive: bool,
+    /// Whether `.` matches `\n` (flag `s`).
+    pub dot_matches_new_line: bool,
+}
+
+/// Named regexes that can be referred to as `{NAME}` in other regexes, like the definitions
+/// section of flex.
+///
+/// Definitions may refer to each other in any order as long as there is no cycle. A definition
+/// is parsed the first time it is referred to, and lowered to NFA once for every set of flags in
+/// effect at its references, later references clone the NFA.
+#[derive(Debug, Default)]
+pub struct Definitions {
+    patterns: FxHashMap<String, String>,
+    parsed: RefCell<FxHashMap<String, Regex>>,
+    compiled: RefCell<FxHashMap<(String, RegexOptions), NFA>>,
+    /// Definitions being parsed, used to detect cycles.
+    parsing: RefCell<Vec<String>>,
+}
+
+impl Definitions {
+    /// Constructs an empty set of definitions.
+    pub fn new() -> Self {
+        Definitions::default()
+    }
+
+    /// Defines `name` as `regex`, replacing any previous definition of it.
+    ///
+    /// Names are referred to as `{NAME}`, so they should start with a letter or '_' and only
+    /// contain letters, digits and '_'.
+    pub fn define(&mut self, name: &str, regex: &str) {
+        self.patterns.insert(name.to_string(), regex.to_string());
+        self.parsed.get_mut().clear();
+        self.compiled.get_mut().clear();
+    }
+
+    /// Whether `name` is defined.
+    pub fn contains(&self, name: &str) -> bool {
+        self.patterns.contains_key(name)
+    }
+
+    /// Syntax tree of the definition of `name`, with the definitions it refers to checked.
+    ///
+    /// Panics if `name` is not defined.
+    fn parse(&self, name: &str) -> Result<Regex, RegexError> {
+        if let Some(regex) = self.parsed.borrow().get(name) {
+            return Ok(regex.clone());
+        }
+        self.parsing.borrow_mut().push(name.to_string());
+        let ret = parse_all(Parser::new(&self.patterns[name], Some(self)), true, true);
+        self.parsing.borrow_mut().pop();
+        let regex = ret?;
+        self.parsed
+            .borrow_mut()
+            .insert(name.to_string(), regex.clone());
+        Ok(regex)
+    }
+}
+
+/// Flags set by `(?flags)` or `(?flags:...)`, `None` for the flags left unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Flags {
+    /// Flag `i`, see `RegexOptions::case_insensitive`.
+    pub case_insensitive: Option<bool>,
+    /// Flag `s`, see `RegexOptions::dot_matches_new_line`.
+    pub dot_matches_new_line: Option<bool>,
+}
+
+impl Flags {
+    /// Sets the flags in `options`.
+    pub fn apply(&self, options: &mut RegexOptions) {
+        if let Some(value) = self.case_insensitive {
+            options.case_insensitive = value;
+        }
+        if let Some(value) = self.dot_matches_new_line {
+            options.dot_matches_new_line = value;
+        }
+    }
+}
+
+/// Prints the flags like `is-i`, without the surrounding `(?` and `)`.
+impl Display for Flags {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let flags = [
+            ('i', self.case_insensitive),
+            ('s', self.dot_matches_new_line),
+        ];
+        for &(ch, _) in flags.iter().filter(|&&(_, value)| value == Some(true)) {
+            write!(f, "{}", ch)?;
+        }
+        if flags.iter().any(|&(_, value)| value == Some(false)) {
+            write!(f, "-")?;
+        }
+        for &(ch, _) in flags.iter().filter(|&&(_, value)| value == Some(false)) {
+            write!(f, "{}", ch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Abstract syntax tree of a regex, as returned by `parse`.
+///
+/// The tree follows the syntax closely, groups and inline flags are kept as they are written.
+/// Escapes are resolved though, and class escapes like `\d` or `[[:alpha:]]` are expanded to the
+/// intervals they stand for. Flags only take effect when the tree is lowered to NFA by `to_nfa`.
+///
+/// `Display` prints a pattern that parses to an equivalent tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Regex {
+    /// Matches the empty string.
+    Empty,
+    /// A single char.
+    Literal(char),
+    /// Any char `.`, it matches `\n` only with the `s` flag.
+    Any,
+    /// The start of a line `^`, only matching at the start of a token.
+    LineStart,
+    /// The end of a line or the input `$`, only matching at the end of a token.
+    LineEnd,
+    /// A char class, `ranges` are sorted disjoint closed intervals of the chars in the class
+    /// before negation.
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// A reference `{NAME}` to a named regex in `Definitions`.
+    Reference(String),
+    /// A group `(...)`, flags set inside it do not leak out of it.
+    Group(Box<Regex>),
+    /// Inline flags `(?flags)`, which apply to the rest of the enclosing group.
+    Flags(Flags),
+    /// A group with flags `(?flags:...)`, the flags apply inside it only.
+    ScopedFlags(Flags, Box<Regex>),
+    /// Repetition of `regex` at least `min` times and at most `max` times (unbounded if `None`).
+    Repeat {
+        regex: Box<Regex>,
+        min: usize,
+        max: Option<usize>,
+    },
+    /// Complement `~regex`, matching any string of chars not matched by `regex`.
+    Complement(Box<Regex>),
+    /// Concatenation, `parse` produces it with at least two regexes.
+    Concat(Vec<Regex>),
+    /// Intersection `&&`, matching the strings matched by all the regexes. `parse` produces it
+    /// with at least two regexes.
+    Intersection(Vec<Regex>),
+    /// Branching `|`, `parse` produces it with at least two regexes.
+    Alternation(Vec<Regex>),
+}
+
+/// Chars escaped when printing a regex.
+const SPECIAL_CHARS: &str = "\\()[]{}|*+?.^$&~";
+
+/// Chars escaped when printing a char class.
+const CLASS_SPECIAL_CHARS: &str = "\\[]^-";
+
+/// Prints `ch` so that it is parsed back as the same char, escaping it if it is in `special`.
+fn write_char(f: &mut Formatter, ch: char, special: &str) -> fmt::Result {
+    match ch {
+        '\0' => write!(f, "\\0"),
+        '\n' => write!(f, "\\n"),
+        '\r' => write!(f, "\\r"),
+        '\t' => write!(f, "\\t"),
+        ch if special.contains(ch) => write!(f, "\\{}", ch),
+        ch if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32),
+        ch => write!(f, "{}", ch),
+    }
+}
+
+impl Regex {
+    /// How tightly the regex binds when printed, lower ones need parentheses in places
+    /// expecting higher ones.
+    fn precedence(&self) -> u8 {
+        match self {
+            Regex::Alternation(_) => 0,
+            Regex::Intersection(_) => 1,
+            Regex::Concat(_) | Regex::Empty => 2,
+            Regex::Complement(_) => 3,
+            Regex::Repeat { .. } => 4,
+            _ => 5,
+        }
+    }
+
+    /// Prints the regex, parenthesized if it binds less tightly than `precedence`.
+    fn fmt_with(&self, f: &mut Formatter, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "(")?;
+            self.fmt_with(f, 0)?;
+            return write!(f, ")");
+        }
+        match self {
+            Regex::Empty => Ok(()),
+            Regex::Literal(ch) => write_char(f, *ch, SPECIAL_CHARS),
+            Regex::Any => write!(f, "."),
+            Regex::LineStart => write!(f, "^"),
+            Regex::LineEnd => write!(f, "$"),
+            Regex::Class { ranges, negated } => {
+                write!(f, "[{}", if *negated { "^" } else { "" })?;
+                for &(l, r) in ranges {
+                    write_char(f, l, CLASS_SPECIAL_CHARS)?;
+                    if l < r {
+                        write!(f, "-")?;
+                        write_char(f, r, CLASS_SPECIAL_CHARS)?;
                     }
                 }
-                Ok(val as char)
-            }
-            Some('u') => {
-                if let Some('{') = {
-                    it.next();
-                    it.peek()
-                } {
-                    let mut val = 0u32;
-                    // Expecting several hex digits terminated by a '}'
-                    loop {
-                        match {
-                            it.next();
-                            it.peek()
-                        } {
-                            Some(ch) if ch.is_digit(16) => {
-                                val = (val << 4) + ch.to_digit(16).unwrap();
-                            }
-                            Some('}') => break from_u32(val).ok_or("Invalid unicode in escape"),
-                            _ => break Err("Expecting '}' or hexadecimal digits"),
-                        }
+                write!(f, "]")
+            }
+            Regex::Reference(name) => write!(f, "{{{}}}", name),
+            Regex::Group(regex) => {
+                write!(f, "(")?;
+                regex.fmt_with(f, 0)?;
+                write!(f, ")")
+            }
+            Regex::Flags(flags) => write!(f, "(?{})", flags),
+            Regex::ScopedFlags(flags, regex) => {
+                write!(f, "(?{}:", flags)?;
+                regex.fmt_with(f, 0)?;
+                write!(f, ")")
+            }
+            Regex::Repeat { regex, min, max } => {
+                regex.fmt_with(f, 4)?;
+                match (min, max) {
+                    (0, None) => write!(f, "*"),
+                    (1, None) => write!(f, "+"),
+                    (0, Some(1)) => write!(f, "?"),
+                    (min, None) => write!(f, "{{{},}}", min),
+                    (min, Some(max)) if min == max => write!(f, "{{{}}}", min),
+                    (min, Some(max)) => write!(f, "{{{},{}}}", min, max),
+                }
+            }
+            Regex::Complement(regex) => {
+                write!(f, "~")?;
+                regex.fmt_with(f, 3)
+            }
+            Regex::Concat(regexes) => regexes.iter().try_for_each(|regex| regex.fmt_with(f, 3)),
+            Regex::Intersection(regexes) => {
+                for (i, regex) in regexes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "&&")?;
                     }
-                } else {
-                    Err("Invalid unicode escape")
+                    regex.fmt_with(f, 2)?;
                 }
+                Ok(())
             }
-            Some(ch) => Ok(*ch),
-            None => Err("Expecting a character after escape '\\'"),
-        },
-        Some('(') => Err("Expecting character, found '('"),
-        Some(')') => Err("Expecting character, found ')'"),
-        Some('[') => Err("Expecting character, found '['"),
-        Some(']') => Err("Expecting character, found ']'"),
-        Some('|') => Err("Expecting character, found '|'"),
-        Some('*') => Err("Expecting character, found '*'"),
-        Some('+') => Err("Expecting character, found '+'"),
-        Some('?') => Err("Expecting character, found '?'"),
-        Some('.') => Err("Expecting character, found '.'"),
-        Some('^') => Err("Expecting character, found '^'"),
-        Some(ch) => Ok(*ch),
-        None => Err("Expecting character, found end of string"),
+            Regex::Alternation(regexes) => {
+                for (i, regex) in regexes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    regex.fmt_with(f, 1)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Lowers the regex to NFA, starting with the flags in `options`. References are resolved
+    /// in `definitions`.
+    ///
+    /// Errors not located by `parse`, like undefined names, span the whole printed regex.
+    pub fn to_nfa(
+        &self,
+        mut options: RegexOptions,
+        definitions: &Definitions,
+    ) -> Result<NFA, RegexError> {
+        if let Err((_, ch)) = check_anchors(self, true, true, Some(definitions), &mut 0) {
+            return Err(lowering_error(self, RegexErrorKind::MisplacedAnchor(ch)));
+        }
+        if expansion(self, Some(definitions)) > REPETITION_LIMIT {
+            return Err(lowering_error(self, RegexErrorKind::RepetitionTooLarge));
+        }
+        lower(self, &mut options, definitions)
+    }
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.fmt_with(f, 0)
+    }
+}
+
+/// Kinds of errors raised while compiling a regex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegexErrorKind {
+    /// Something else was expected at this position, `found` is `None` at the end of the pattern.
+    Expected {
+        expected: &'static str,
+        found: Option<char>,
+    },
+    /// A `(` that is never closed.
+    UnclosedGroup,
+    /// A `)` without a matching `(`.
+    UnopenedGroup,
+    /// A `[` that is never closed.
+    UnclosedClass,
+    /// A char class that matches nothing.
+    EmptyClass,
+    /// An interval like `z-a` whose end is less than its start.
+    InvalidInterval,
+    /// An escape sequence that does not denote a valid char.
+    InvalidEscape,
+    /// A class escape like `\d` used where a single char is needed, e.g. `[a-\d]`.
+    ClassEscape,
+    /// An unknown POSIX class name in `[[:name:]]`.
+    UnknownPosixClass(String),
+    /// An unknown Unicode property in `\p{name}`.
+    UnknownProperty(String),
+    /// An unknown flag in `(?flags)`.
+    UnknownFlag(char),
+    /// A counted repetition whose upper bound is less than its lower bound.
+    InvalidRepetition,
+    /// A counted repetition with a count larger than `REPETITION_LIMIT`, or whose count times
+    /// the counts of the repetitions nested in it is.
+    RepetitionTooLarge,
+    /// An anchor `^` that is not at the start of the regex, or `$` that is not at its end. The
+    /// lexer only checks anchors around a token, so such a regex would never match.
+    MisplacedAnchor(char),
+    /// A reference `{NAME}` to a name that is not defined.
+    UndefinedName(String),
+    /// A definition that refers to itself, the names on the cycle are listed in order.
+    RecursiveDefinition(Vec<String>),
+    /// A feature that cannot be compiled into an automaton.
+    Unsupported(&'static str),
+    /// An error reported by regex-syntax, see `compile_regex_syntax`.
+    Syntax(String),
+}
+
+/// Error raised when a regex cannot be compiled.
+///
+/// The `Display` implementation prints the pattern with the offending part underlined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError {
+    pub kind: RegexErrorKind,
+    /// Byte range of the offending part in `pattern`.
+    pub span: Range<usize>,
+    pub pattern: String,
+}
+
+impl Display for RegexErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RegexErrorKind::Expected {
+                expected,
+                found: Some(ch),
+            } => write!(f, "expecting {}, found {:?}", expected, ch),
+            RegexErrorKind::Expected {
+                expected,
+                found: None,
+            } => write!(f, "expecting {}, found end of pattern", expected),
+            RegexErrorKind::UnclosedGroup => write!(f, "unclosed group, missing ')'"),
+            RegexErrorKind::UnopenedGroup => write!(f, "unopened group, missing '('"),
+            RegexErrorKind::UnclosedClass => write!(f, "unclosed char class, missing ']'"),
+            RegexErrorKind::EmptyClass => write!(f, "char class matches nothing"),
+            RegexErrorKind::InvalidInterval => {
+                write!(f, "invalid interval, the end is less than the start")
+            }
+            RegexErrorKind::InvalidEscape => write!(f, "escape sequence is not a valid char"),
+            RegexErrorKind::ClassEscape => write!(f, "char class escape used as a single char"),
+            RegexErrorKind::UnknownPosixClass(name) => write!(f, "unknown POSIX class {:?}", name),
+            RegexErrorKind::UnknownProperty(name) => {
+                write!(f, "unknown Unicode property {:?}", name)
+            }
+            RegexErrorKind::UnknownFlag(ch) => write!(f, "unknown flag {:?}", ch),
+            RegexErrorKind::InvalidRepetition => {
+                write!(
+                    f,
+                    "invalid repetition, the upper bound is less than the lower bound"
+                )
+            }
+            RegexErrorKind::RepetitionTooLarge => write!(
+                f,
+                "repetition count (multiplied by nested ones) is larger than the limit {}",
+                REPETITION_LIMIT
+            ),
+            RegexErrorKind::MisplacedAnchor('^') => {
+                write!(f, "anchor '^' is not at the start of the regex")
+            }
+            RegexErrorKind::MisplacedAnchor(ch) => {
+                write!(f, "anchor {:?} is not at the end of the regex", ch)
+            }
+            RegexErrorKind::UndefinedName(name) => write!(f, "undefined name {:?}", name),
+            RegexErrorKind::RecursiveDefinition(names) => {
+                write!(f, "recursive definition {}", names.join(" -> "))
+            }
+            RegexErrorKind::Unsupported(what) => write!(f, "{} are not supported", what),
+            RegexErrorKind::Syntax(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Display for RegexError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let col = self.pattern[..self.span.start].chars().count();
+        let width = self.pattern[self.span.clone()].chars().count();
+        writeln!(f, "{} (at byte {})", self.kind, self.span.start)?;
+        writeln!(f, "    {}", self.pattern)?;
+        write!(
+            f,
+            "    {}{}",
+            " ".repeat(col),
+            "^".repeat(cmp::max(width, 1))
+        )
+    }
+}
+
+impl Error for RegexError {}
+
+/// Input of the recursive descent parser, keeps track of the byte position in the pattern.
+struct Parser<'a> {
+    pattern: &'a str,
+    it: Peekable<CharIndices<'a>>,
+    /// Definitions references are checked against, if any.
+    definitions: Option<&'a Definitions>,
+    /// Byte ranges of the anchors and references in the pattern, in order, used to locate
+    /// misplaced anchors.
+    anchors: Vec<Range<usize>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(pattern: &'a str, definitions: Option<&'a Definitions>) -> Self {
+        Parser {
+            pattern,
+            it: pattern.char_indices().peekable(),
+            definitions,
+            anchors: Vec::new(),
+        }
+    }
+
+    /// The next char, without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        self.it.peek().map(|&(_, ch)| ch)
+    }
+
+    /// The char after the next one, without consuming anything.
+    fn peek_second(&self) -> Option<char> {
+        self.it.clone().nth(1).map(|(_, ch)| ch)
+    }
+
+    /// Consumes the next char.
+    fn bump(&mut self) -> Option<char> {
+        self.it.next().map(|(_, ch)| ch)
+    }
+
+    /// Byte position of the next char, or the length of the pattern at its end.
+    fn pos(&mut self) -> usize {
+        self.it.peek().map_or(self.pattern.len(), |&(i, _)| i)
+    }
+
+    /// Error spanning from `start` to the current position.
+    fn error_from(&mut self, start: usize, kind: RegexErrorKind) -> RegexError {
+        RegexError {
+            kind,
+            span: start..self.pos(),
+            pattern: self.pattern.to_string(),
+        }
+    }
+
+    /// Error at the next char.
+    fn error_here(&mut self, kind: RegexErrorKind) -> RegexError {
+        let start = self.pos();
+        let end = start + self.peek().map_or(0, char::len_utf8);
+        RegexError {
+            kind,
+            span: start..end,
+            pattern: self.pattern.to_string(),
+        }
+    }
+
+    /// Error saying that `expected` was expected instead of the next char.
+    fn expected(&mut self, expected: &'static str) -> RegexError {
+        let found = self.peek();
+        self.error_here(RegexErrorKind::Expected { expected, found })
+    }
+}
+
+fn parse_char(p: &mut Parser) -> Result<char, RegexError> {
+    match p.peek() {
+        Some('\\') => {
+            p.bump();
+            parse_escape(p)
+        }
+        Some('(') | Some(')') | Some('[') | Some(']') | Some('|') | Some('*') | Some('+')
+        | Some('?') | Some('.') | Some('^') | None => Err(p.expected("a character")),
+        Some(ch) => {
+            p.bump();
+            Ok(ch)
+        }
+    }
+}
+
+/// Parses an escape sequence, the leading '\\' should have been consumed already.
+fn parse_escape(p: &mut Parser) -> Result<char, RegexError> {
+    // The span of errors starts at the '\\'
+    let start = p.pos() - 1;
+    let ch = match p.peek() {
+        Some(ch) => ch,
+        None => return Err(p.expected("a character after escape '\\'")),
     };
-    it.next();
+    p.bump();
+    match ch {
+        '0' => Ok('\0'),
+        'n' => Ok('\n'),
+        'r' => Ok('\r'),
+        't' => Ok('\t'),
+        'x' => {
+            let mut val = 0u8;
+            // Expecting 2 hex digits
+            for _ in 0..2 {
+                match p.peek().and_then(|ch| ch.to_digit(16)) {
+                    Some(d) => {
+                        p.bump();
+                        val = (val << 4) + d as u8
+                    }
+                    None => return Err(p.expected("a hexadecimal digit")),
+                }
+            }
+            Ok(val as char)
+        }
+        'u' => {
+            if p.peek() != Some('{') {
+                return Err(p.expected("'{'"));
+            }
+            p.bump();
+            let mut val = 0u32;
+            // Expecting several hex digits terminated by a '}'
+            loop {
+                match p.peek() {
+                    Some('}') => {
+                        p.bump();
+                        break from_u32(val)
+                            .ok_or_else(|| p.error_from(start, RegexErrorKind::InvalidEscape));
+                    }
+                    Some(ch) if ch.is_ascii_hexdigit() => {
+                        p.bump();
+                        val = val.saturating_mul(16) + ch.to_digit(16).unwrap();
+                    }
+                    _ => break Err(p.expected("'}' or a hexadecimal digit")),
+                }
+            }
+        }
+        'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'p' | 'P' => {
+            Err(p.error_from(start, RegexErrorKind::ClassEscape))
+        }
+        ch => Ok(ch),
+    }
+}
+
+/// Adds the closed interval `[l, r]` to the endpoints of a char class.
+fn add_interval(endpoints: &mut BTreeMap<u32, i32>, l: u32, r: u32) {
+    *endpoints.entry(l).or_insert(0) += 1;
+    *endpoints.entry(r + 1).or_insert(0) -= 1;
+}
+
+/// Sweeps the endpoints of a char class, returning the disjoint closed intervals
+/// covered by at least one interval, or by none of them if `negate` is set.
+fn sweep(endpoints: &BTreeMap<u32, i32>, negate: bool) -> Vec<(u32, u32)> {
+    let mut ret = Vec::new();
+    let mut cov = 0;
+    let mut begin: Option<u32> = if !negate { None } else { Some(0) };
+    for (&i, &j) in endpoints.iter() {
+        cov += j;
+        let in_interval = (cov > 0) ^ negate;
+        match begin {
+            None if in_interval => begin = Some(i),
+            Some(v) if !in_interval => {
+                if v < i {
+                    ret.push((v, i - 1));
+                }
+                begin = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(v) = begin {
+        if v <= MAX_CHAR {
+            ret.push((v, MAX_CHAR));
+        }
+    }
     ret
 }
 
-fn parse_class(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    let negate = if let Some('^') = {
-        it.next();
-        it.peek()
-    } {
-        it.next();
+/// Converts the intervals to char intervals, skipping surrogates.
+fn to_chars(ranges: &[(u32, u32)]) -> Vec<(char, char)> {
+    let mut intervals = Vec::with_capacity(ranges.len());
+    for &(mut l, mut r) in ranges {
+        if SURROGATES.0 <= l && l <= SURROGATES.1 {
+            l = SURROGATES.1 + 1;
+        }
+        if SURROGATES.0 <= r && r <= SURROGATES.1 {
+            r = SURROGATES.0 - 1;
+        }
+        if let (true, Some(l), Some(r)) = (l <= r, from_u32(l), from_u32(r)) {
+            intervals.push((l, r));
+        }
+    }
+    intervals
+}
+
+/// Constructs the NFA accepting any char in the given intervals, surrogates are skipped.
+///
+/// Returns `None` if there is no char in the intervals.
+fn ranges_to_nfa(ranges: &[(u32, u32)]) -> Option<NFA> {
+    let intervals = to_chars(ranges);
+    if intervals.is_empty() {
+        None
+    } else {
+        Some(NFA::from(&intervals[..]))
+    }
+}
+
+/// Complement of the (disjoint) intervals.
+fn negated(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut endpoints = BTreeMap::new();
+    for &(l, r) in ranges {
+        add_interval(&mut endpoints, l, r);
+    }
+    sweep(&endpoints, true)
+}
+
+/// Adds the other cases of every char in the intervals, using simple Unicode case folding.
+fn case_fold(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut class = ClassUnicode::new(
+        to_chars(ranges)
+            .into_iter()
+            .map(|(l, r)| ClassUnicodeRange::new(l, r)),
+    );
+    class.case_fold_simple();
+    class
+        .iter()
+        .map(|r| (r.start() as u32, r.end() as u32))
+        .collect()
+}
+
+/// Constructs the NFA of a single char, also accepting its other cases if case insensitive.
+fn char_to_nfa(ch: char, options: &RegexOptions) -> NFA {
+    if options.case_insensitive {
+        NFA::from(&to_chars(&case_fold(&[(ch as u32, ch as u32)]))[..])
+    } else {
+        NFA::from(ch)
+    }
+}
+
+/// Intervals of the Perl-style shorthand class `\ch` (`\d`, `\w`, `\s` and their negations),
+/// these follow the ASCII definitions.
+fn perl_class(ch: char) -> Option<Vec<(u32, u32)>> {
+    let (ranges, negate): (&[(char, char)], bool) = match ch {
+        'd' => (&[('0', '9')], false),
+        'D' => (&[('0', '9')], true),
+        'w' => (&[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], false),
+        'W' => (&[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], true),
+        's' => (&[('\t', '\r'), (' ', ' ')], false),
+        'S' => (&[('\t', '\r'), (' ', ' ')], true),
+        _ => return None,
+    };
+    let mut endpoints = BTreeMap::new();
+    for &(l, r) in ranges {
+        add_interval(&mut endpoints, l as u32, r as u32);
+    }
+    Some(sweep(&endpoints, negate))
+}
+
+/// Intervals of the Unicode property `name`, which can be a general category (`L`, `Letter`),
+/// a script (`Greek`, `Script=Greek`) or a binary property (`XID_Start`).
+///
+/// The tables are the ones shipped with `regex-syntax`.
+fn unicode_class(name: &str, negate: bool) -> Option<Vec<(u32, u32)>> {
+    let valid = |ch: char| ch.is_ascii_alphanumeric() || " _-=:".contains(ch);
+    if name.is_empty() || !name.chars().all(valid) {
+        return None;
+    }
+    let hir = ParserBuilder::new()
+        .build()
+        .parse(&format!("\\p{{{}}}", name))
+        .ok()?;
+    let mut endpoints = BTreeMap::new();
+    if let HirKind::Class(Class::Unicode(class)) = hir.kind() {
+        for r in class.iter() {
+            add_interval(&mut endpoints, r.start() as u32, r.end() as u32);
+        }
+    }
+    Some(sweep(&endpoints, negate))
+}
+
+/// Parses the Unicode property class `\p{name}`, `\P{name}` or the one letter form `\pL`,
+/// with the parser at the 'p' or 'P' following '\\'.
+fn parse_unicode_class(p: &mut Parser) -> Result<Vec<(u32, u32)>, RegexError> {
+    let start = p.pos() - 1;
+    let negate = p.bump() == Some('P');
+    let name = match p.peek() {
+        Some('{') => {
+            p.bump();
+            let mut name = String::new();
+            loop {
+                match p.peek() {
+                    Some('}') => {
+                        p.bump();
+                        break name;
+                    }
+                    Some(ch) => {
+                        p.bump();
+                        name.push(ch)
+                    }
+                    None => return Err(p.expected("'}' to close the Unicode property")),
+                }
+            }
+        }
+        Some(ch) => {
+            p.bump();
+            ch.to_string()
+        }
+        None => return Err(p.expected("a Unicode property name")),
+    };
+    match unicode_class(&name, negate) {
+        Some(ranges) => Ok(ranges),
+        None => Err(p.error_from(start, RegexErrorKind::UnknownProperty(name))),
+    }
+}
+
+/// Parses the class escapes (`\d`, `\p{L}` etc), with the parser at the char following '\\'.
+///
+/// Returns `None` without consuming anything if it is not a class escape.
+fn parse_class_escape(p: &mut Parser) -> Result<Option<Vec<(u32, u32)>>, RegexError> {
+    match p.peek() {
+        Some('p') | Some('P') => parse_unicode_class(p).map(Some),
+        Some(ch) => {
+            let ranges = perl_class(ch);
+            if ranges.is_some() {
+                p.bump();
+            }
+            Ok(ranges)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Intervals of the POSIX class `[:name:]`, these follow the ASCII definitions.
+fn posix_class(name: &str) -> Option<&'static [(char, char)]> {
+    Some(match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "ascii" => &[('\0', '\x7f')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "word" => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    })
+}
+
+/// Parses a POSIX class like `[:alpha:]` or `[:^alpha:]` inside a char class
+/// (with the parser at the leading '[') and adds it to `endpoints`.
+fn parse_posix_class(p: &mut Parser, endpoints: &mut BTreeMap<u32, i32>) -> Result<(), RegexError> {
+    let start = p.pos();
+    p.bump();
+    if p.peek() != Some(':') {
+        return Err(p.expected("':' to start a POSIX class"));
+    }
+    p.bump();
+    let negate = if let Some('^') = p.peek() {
+        p.bump();
+        true
+    } else {
+        false
+    };
+    let mut name = String::new();
+    while let Some(ch) = p.peek().filter(char::is_ascii_alphabetic) {
+        p.bump();
+        name.push(ch);
+    }
+    for &expected in &[':', ']'] {
+        if p.peek() != Some(expected) {
+            return Err(p.expected("':]' to close a POSIX class"));
+        }
+        p.bump();
+    }
+    let ranges = match posix_class(&name) {
+        Some(ranges) => ranges,
+        None => return Err(p.error_from(start, RegexErrorKind::UnknownPosixClass(name))),
+    };
+    let mut class = BTreeMap::new();
+    for &(l, r) in ranges {
+        add_interval(&mut class, l as u32, r as u32);
+    }
+    for (l, r) in sweep(&class, negate) {
+        add_interval(endpoints, l, r);
+    }
+    Ok(())
+}
+
+/// Parses a single char inside a char class, where only '\\', '[' and ']' are special.
+fn parse_class_char(p: &mut Parser) -> Result<char, RegexError> {
+    match p.peek() {
+        Some('\\') => {
+            p.bump();
+            parse_escape(p)
+        }
+        Some('[') | Some(']') | None => Err(p.expected("a character")),
+        Some(ch) => {
+            p.bump();
+            Ok(ch)
+        }
+    }
+}
+
+/// Parses an item of a char class and adds it to `endpoints`, an item is either
+/// a char, an interval like `a-z`, a Perl class like `\d` or a POSIX class like `[:alpha:]`.
+fn parse_class_item(p: &mut Parser, endpoints: &mut BTreeMap<u32, i32>) -> Result<(), RegexError> {
+    let start = p.pos();
+    let l = match p.peek() {
+        Some('[') => return parse_posix_class(p, endpoints),
+        Some('\\') => {
+            p.bump();
+            if let Some(ranges) = parse_class_escape(p)? {
+                for (l, r) in ranges {
+                    add_interval(endpoints, l, r);
+                }
+                return Ok(());
+            }
+            parse_escape(p)?
+        }
+        _ => parse_class_char(p)?,
+    };
+    let r = match p.peek() {
+        Some('-') => {
+            p.bump();
+            if let Some(']') = p.peek() {
+                // A trailing '-' is taken literally
+                add_interval(endpoints, '-' as u32, '-' as u32);
+                l
+            } else {
+                parse_class_char(p)?
+            }
+        }
+        _ => l,
+    };
+    if r < l {
+        return Err(p.error_from(start, RegexErrorKind::InvalidInterval));
+    }
+    add_interval(endpoints, l as u32, r as u32);
+    Ok(())
+}
+
+/// Checks that the char class is not empty, converting the intervals to chars.
+fn class(
+    p: &mut Parser,
+    start: usize,
+    ranges: &[(u32, u32)],
+    negate: bool,
+) -> Result<Regex, RegexError> {
+    let matched = if negate {
+        negated(ranges)
+    } else {
+        ranges.to_vec()
+    };
+    if to_chars(&matched).is_empty() {
+        return Err(p.error_from(start, RegexErrorKind::EmptyClass));
+    }
+    Ok(Regex::Class {
+        ranges: to_chars(ranges),
+        negated: negate,
+    })
+}
+
+fn parse_class(p: &mut Parser) -> Result<Regex, RegexError> {
+    let start = p.pos();
+    p.bump();
+    let negate = if let Some('^') = p.peek() {
+        p.bump();
         true
     } else {
         false
     };
     let mut endpoints: BTreeMap<u32, i32> = BTreeMap::new();
     loop {
-        match it.peek() {
+        match p.peek() {
             Some(']') => {
-                it.next();
-                let mut cov = 0;
-                let mut begin: Option<u32> = if !negate { None } else { Some(0) };
-                let mut nfa: Option<NFA> = None;
-                let mut last = 0;
-                for (i, j) in endpoints.iter() {
-                    cov += j;
-                    last = *i;
-                    let in_interval = (cov > 0) ^ negate;
-                    match begin {
-                        None if in_interval => begin = Some(*i),
-                        Some(v) if !in_interval => {
-                            let l = from_u32(v).unwrap();
-                            let r = from_u32(i - 1).unwrap();
-                            let n = NFA::from((l, r));
-                            nfa = Some(if let Some(prev) = nfa { prev | n } else { n });
-                            begin = None;
-                        }
-                        _ => {}
-                    }
-                }
-                if negate {
-                    let n = NFA::from((from_u32(last).unwrap(), '\u{ffff}'));
-                    nfa = Some(if let Some(prev) = nfa { prev | n } else { n });
-                }
-                break nfa.ok_or("NFA not constructed for char class!");
-            }
-            None => {
-                break Err("Missing ']' at the end of a char class");
-            }
-            _ => {
-                let l = parse_char(it)? as u32;
-                let r = if let Some('-') = it.peek() {
-                    it.next();
-                    parse_char(it)? as u32 + 1
-                } else {
-                    l + 1
-                };
-                if let Some(val) = endpoints.get_mut(&l) {
-                    *val += 1;
-                } else {
-                    endpoints.insert(l, 1);
-                }
-                if let Some(val) = endpoints.get_mut(&r) {
-                    *val -= 1;
-                } else {
-                    endpoints.insert(r, -1);
-                }
+                p.bump();
+                break class(p, start, &sweep(&endpoints, false), negate);
             }
+            None => break Err(p.error_from(start, RegexErrorKind::UnclosedClass)),
+            _ => parse_class_item(p, &mut endpoints)?,
         }
     }
 }
 
-fn parse_group(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    it.next();
-    let ret = parse_regex(it);
-    if let Some(')') = it.peek() {
-        it.next();
-        ret
+/// Parses the flags of `(?flags)` or `(?flags:...)`, with the parser right after the '?',
+/// returning the flags and whether they are followed by ':'.
+fn parse_flags(p: &mut Parser) -> Result<(Flags, bool), RegexError> {
+    let mut flags = Flags::default();
+    let mut enable = true;
+    loop {
+        let start = p.pos();
+        match p.bump() {
+            Some('-') if enable => enable = false,
+            Some('i') => flags.case_insensitive = Some(enable),
+            Some('s') => flags.dot_matches_new_line = Some(enable),
+            Some(':') => break Ok((flags, true)),
+            Some(')') => break Ok((flags, false)),
+            Some(ch) => break Err(p.error_from(start, RegexErrorKind::UnknownFlag(ch))),
+            None => break Err(p.expected("')' to close the flags")),
+        }
+    }
+}
+
+fn parse_group(p: &mut Parser) -> Result<Regex, RegexError> {
+    let start = p.pos();
+    p.bump();
+    let mut flags = None;
+    if let Some('?') = p.peek() {
+        p.bump();
+        match parse_flags(p)? {
+            (inline, false) => return Ok(Regex::Flags(inline)),
+            (scoped, true) => flags = Some(scoped),
+        }
+    }
+    let regex = Box::new(parse_regex(p)?);
+    if let Some(')') = p.peek() {
+        p.bump();
+        Ok(match flags {
+            Some(flags) => Regex::ScopedFlags(flags, regex),
+            None => Regex::Group(regex),
+        })
     } else {
-        Err("Expecting ')' to match with '('")
+        Err(RegexError {
+            kind: RegexErrorKind::UnclosedGroup,
+            span: start..start + 1,
+            pattern: p.pattern.to_string(),
+        })
+    }
+}
+
+/// Parses a reference `{NAME}`. When parsing against definitions, the name must be defined
+/// and the definition is parsed too, so that errors in it and cycles are reported.
+fn parse_reference(p: &mut Parser) -> Result<Regex, RegexError> {
+    let start = p.pos();
+    p.bump();
+    let mut name = String::new();
+    while let Some(ch) = p.peek().filter(|&ch| ch.is_alphanumeric() || ch == '_') {
+        p.bump();
+        name.push(ch);
+    }
+    if p.peek() != Some('}') {
+        return Err(p.expected("'}' to close the name"));
+    }
+    p.bump();
+    if let Some(definitions) = p.definitions {
+        if !definitions.contains(&name) {
+            return Err(p.error_from(start, RegexErrorKind::UndefinedName(name)));
+        }
+        let cycle = {
+            let parsing = definitions.parsing.borrow();
+            parsing.iter().position(|n| *n == name).map(|i| {
+                let mut cycle = parsing[i..].to_vec();
+                cycle.push(name.clone());
+                cycle
+            })
+        };
+        if let Some(cycle) = cycle {
+            return Err(p.error_from(start, RegexErrorKind::RecursiveDefinition(cycle)));
+        }
+        definitions.parse(&name)?;
     }
+    let end = p.pos();
+    p.anchors.push(start..end);
+    Ok(Regex::Reference(name))
 }
 
-fn parse_elementary(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    match it.peek() {
-        Some('[') => parse_class(it),
-        Some('(') => parse_group(it),
+/// Whether the next '{' starts a reference rather than a counted repetition.
+fn at_reference(p: &Parser) -> bool {
+    p.peek_second()
+        .is_some_and(|ch| ch.is_alphabetic() || ch == '_')
+}
+
+fn parse_elementary(p: &mut Parser) -> Result<Regex, RegexError> {
+    let start = p.pos();
+    match p.peek() {
+        Some('[') => parse_class(p),
+        Some('(') => parse_group(p),
+        Some('{') if at_reference(p) => parse_reference(p),
         Some('.') => {
-            it.next();
-            Ok(NFA::from(('\0', '\u{ffff}')))
+            p.bump();
+            Ok(Regex::Any)
+        }
+        Some('^') => {
+            p.bump();
+            p.anchors.push(start..start + 1);
+            Ok(Regex::LineStart)
         }
-        _ => parse_char(it).map(|c| NFA::from(c)),
+        Some('$') => {
+            p.bump();
+            p.anchors.push(start..start + 1);
+            Ok(Regex::LineEnd)
+        }
+        Some('\\') => {
+            p.bump();
+            match parse_class_escape(p)? {
+                Some(ranges) => class(p, start, &ranges, false),
+                None => parse_escape(p).map(Regex::Literal),
+            }
+        }
+        _ => parse_char(p).map(Regex::Literal),
+    }
+}
+
+/// Parses a (possibly empty) decimal number.
+fn parse_count(p: &mut Parser) -> Option<usize> {
+    let mut val: Option<usize> = None;
+    while let Some(d) = p.peek().and_then(|ch| ch.to_digit(10)) {
+        p.bump();
+        // Saturate so that huge counts are reported as too large instead of overflowing
+        val = Some(
+            val.unwrap_or(0)
+                .saturating_mul(10)
+                .saturating_add(d as usize),
+        );
     }
+    val
 }
 
-fn parse_repetition(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    let mut nfa = parse_elementary(it)?;
+/// Parses the counted repetition `{m}`, `{m,}` or `{m,n}` (with the leading `{` next),
+/// returning the lower and the optional upper bound.
+fn parse_counted(p: &mut Parser) -> Result<(usize, Option<usize>), RegexError> {
+    let start = p.pos();
+    p.bump();
+    let min = match parse_count(p) {
+        Some(min) => min,
+        None => return Err(p.expected("a repetition count")),
+    };
+    let max = if let Some(',') = p.peek() {
+        p.bump();
+        parse_count(p)
+    } else {
+        Some(min)
+    };
+    if p.peek() != Some('}') {
+        return Err(p.expected("'}' to close the repetition"));
+    }
+    p.bump();
+    if max.is_some_and(|max| max < min) {
+        return Err(p.error_from(start, RegexErrorKind::InvalidRepetition));
+    }
+    Ok((min, max))
+}
+
+fn parse_repetition(p: &mut Parser) -> Result<Regex, RegexError> {
+    let mut regex = parse_elementary(p)?;
     loop {
-        match it.peek() {
-            Some('*') => {
-                it.next();
-                nfa = nfa.zero_or_more()
-            }
-            Some('+') => {
-                it.next();
-                nfa = nfa.one_or_more()
-            }
-            Some('?') => {
-                it.next();
-                nfa = nfa.optional()
+        let start = p.pos();
+        let (min, max) = match p.peek() {
+            Some('{') if !at_reference(p) => parse_counted(p)?,
+            Some(ch @ '*') | Some(ch @ '+') | Some(ch @ '?') => {
+                p.bump();
+                match ch {
+                    '*' => (0, None),
+                    '+' => (1, None),
+                    _ => (0, Some(1)),
+                }
             }
-            _ => break Ok(nfa),
+            _ => break Ok(regex),
         };
+        regex = Regex::Repeat {
+            regex: Box::new(regex),
+            min,
+            max,
+        };
+        if expansion(&regex, p.definitions) > REPETITION_LIMIT {
+            return Err(p.error_from(start, RegexErrorKind::RepetitionTooLarge));
+        }
+    }
+}
+
+/// Number of copies a repetition `{min,max}` makes of its regex, as bounded by
+/// `REPETITION_LIMIT`.
+fn repetition_copies(min: usize, max: Option<usize>) -> usize {
+    cmp::max(max.unwrap_or(min), 1)
+}
+
+/// The largest number of copies of any part of `regex` made by the repetitions around it, the
+/// product of their counts. References are only followed if `definitions` are given.
+fn expansion(regex: &Regex, definitions: Option<&Definitions>) -> usize {
+    let max_of = |regexes: &[Regex]| {
+        regexes
+            .iter()
+            .map(|regex| expansion(regex, definitions))
+            .max()
+            .unwrap_or(1)
+    };
+    match regex {
+        Regex::Repeat { regex, min, max } => {
+            repetition_copies(*min, *max).saturating_mul(expansion(regex, definitions))
+        }
+        Regex::Group(inner) | Regex::ScopedFlags(_, inner) | Regex::Complement(inner) => {
+            expansion(inner, definitions)
+        }
+        Regex::Reference(name) => definitions
+            .filter(|d| d.contains(name))
+            .and_then(|d| d.parse(name).ok())
+            .map_or(1, |inner| expansion(&inner, definitions)),
+        Regex::Concat(regexes) | Regex::Intersection(regexes) | Regex::Alternation(regexes) => {
+            max_of(regexes)
+        }
+        _ => 1,
+    }
+}
+
+/// Parses a complement `~regex`, where `regex` is a repetition or another complement.
+fn parse_complement(p: &mut Parser) -> Result<Regex, RegexError> {
+    if let Some('~') = p.peek() {
+        p.bump();
+        Ok(Regex::Complement(Box::new(parse_complement(p)?)))
+    } else {
+        parse_repetition(p)
     }
 }
 
-fn parse_concat(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    let mut nfa = parse_repetition(it)?;
+/// Whether the parser is at the intersection operator `&&`.
+fn at_intersection(p: &mut Parser) -> bool {
+    p.peek() == Some('&') && p.peek_second() == Some('&')
+}
+
+fn parse_concat(p: &mut Parser) -> Result<Regex, RegexError> {
+    let mut regexes = Vec::new();
     loop {
-        match it.peek() {
-            None | Some('|') | Some(')') => break Ok(nfa),
-            _ => nfa = nfa & parse_repetition(it)?,
+        match p.peek() {
+            None | Some('|') | Some(')') => break,
+            _ if at_intersection(p) => break,
+            _ => regexes.push(parse_complement(p)?),
         }
     }
+    Ok(match regexes.len() {
+        0 => Regex::Empty,
+        1 => regexes.pop().unwrap(),
+        _ => Regex::Concat(regexes),
+    })
 }
 
-fn parse_regex(it: &mut Peekable<Chars>) -> Result<NFA, &'static str> {
-    let mut nfa = parse_concat(it)?;
+fn parse_intersection(p: &mut Parser) -> Result<Regex, RegexError> {
+    let mut regexes = vec![parse_concat(p)?];
+    while at_intersection(p) {
+        p.bump();
+        p.bump();
+        regexes.push(parse_concat(p)?);
+    }
+    Ok(if regexes.len() == 1 {
+        regexes.pop().unwrap()
+    } else {
+        Regex::Intersection(regexes)
+    })
+}
+
+fn parse_regex(p: &mut Parser) -> Result<Regex, RegexError> {
+    let mut regexes = vec![parse_intersection(p)?];
     loop {
-        match it.peek() {
+        match p.peek() {
             Some('|') => {
-                it.next();
-                nfa = nfa | parse_concat(it)?;
+                p.bump();
+                regexes.push(parse_intersection(p)?);
             }
-            None | Some(')') => break Ok(nfa),
-            _ => break Err("Expecting '|'"),
+            None | Some(')') => break,
+            _ => return Err(p.expected("'|'")),
         }
     }
+    Ok(if regexes.len() == 1 {
+        regexes.pop().unwrap()
+    } else {
+        Regex::Alternation(regexes)
+    })
 }
 
-pub fn compile_regex(regex: &str) -> Result<NFA, &'static str> {
-    parse_regex(&mut regex.chars().peekable())
+/// Parses the whole pattern, where `at_start` and `at_end` tell whether its edges are edges of the
+/// whole regex, see `check_anchors`.
+fn parse_all(mut p: Parser, at_start: bool, at_end: bool) -> Result<Regex, RegexError> {
+    let regex = parse_regex(&mut p)?;
+    if p.peek().is_some() {
+        // Only a ')' can stop `parse_regex` before the end
+        return Err(p.error_here(RegexErrorKind::UnopenedGroup));
+    }
+    if let Err((i, ch)) = check_anchors(&regex, at_start, at_end, p.definitions, &mut 0) {
+        return Err(RegexError {
+            kind: RegexErrorKind::MisplacedAnchor(ch),
+            span: p.anchors[i].clone(),
+            pattern: p.pattern.to_string(),
+        });
+    }
+    Ok(regex)
+}
+
+/// Whether `regex` only matches the empty string without consuming any char, so that an anchor
+/// next to it is still at an edge of the regex.
+///
+/// Anchors are not, since the lexer only matches one anchor at each edge: `^^a` never matches.
+fn is_zero_width(regex: &Regex) -> bool {
+    matches!(regex, Regex::Empty | Regex::Flags(_))
+}
+
+/// Checks that `^` is only at the start of `regex` and `$` only at its end, where `at_start`
+/// and `at_end` tell whether `regex` itself is at the start and the end of the whole regex.
+///
+/// Anchors and references are numbered in order by `site`, a misplaced anchor is reported with
+/// its number, or with the number of the reference whose definition holds it. References are
+/// only followed if `definitions` are given.
+fn check_anchors(
+    regex: &Regex,
+    at_start: bool,
+    at_end: bool,
+    definitions: Option<&Definitions>,
+    site: &mut usize,
+) -> Result<(), (usize, char)> {
+    match regex {
+        Regex::LineStart | Regex::LineEnd => {
+            let (ch, ok) = match regex {
+                Regex::LineStart => ('^', at_start),
+                _ => ('$', at_end),
+            };
+            *site += 1;
+            if !ok {
+                return Err((*site - 1, ch));
+            }
+        }
+        Regex::Reference(name) => {
+            let index = *site;
+            *site += 1;
+            if let Some(definitions) = definitions.filter(|d| d.contains(name)) {
+                if let Ok(inner) = definitions.parse(name) {
+                    check_anchors(&inner, at_start, at_end, Some(definitions), &mut 0)
+                        .map_err(|(_, ch)| (index, ch))?;
+                }
+            }
+        }
+        Regex::Group(inner) | Regex::ScopedFlags(_, inner) => {
+            check_anchors(inner, at_start, at_end, definitions, site)?
+        }
+        // Only a regex repeated at most once stays at the edges
+        Regex::Repeat { regex, max, .. } => {
+            let once = max.is_some_and(|max| max <= 1);
+            check_anchors(regex, at_start && once, at_end && once, definitions, site)?
+        }
+        Regex::Complement(inner) => check_anchors(inner, false, false, definitions, site)?,
+        Regex::Concat(regexes) => {
+            for (i, regex) in regexes.iter().enumerate() {
+                let start = at_start && regexes[..i].iter().all(is_zero_width);
+                let end = at_end && regexes[i + 1..].iter().all(is_zero_width);
+                check_anchors(regex, start, end, definitions, site)?;
+            }
+        }
+        Regex::Intersection(regexes) | Regex::Alternation(regexes) => {
+            for regex in regexes {
+                check_anchors(regex, at_start, at_end, definitions, site)?;
+            }
+        }
+        Regex::Empty | Regex::Literal(_) | Regex::Any | Regex::Class { .. } | Regex::Flags(_) => {}
+    }
+    Ok(())
+}
+
+/// Parses a regex into its syntax tree, without compiling it.
+///
+/// References `{NAME}` are kept as they are, they are only checked when lowered to NFA.
+pub fn parse(regex: &str) -> Result<Regex, RegexError> {
+    parse_all(Parser::new(regex, None), true, true)
+}
+
+/// Error of the whole printed `regex`, for errors found while lowering it.
+fn lowering_error(regex: &Regex, kind: RegexErrorKind) -> RegexError {
+    let pattern = regex.to_string();
+    RegexError {
+        kind,
+        span: 0..pattern.len(),
+        pattern,
+    }
+}
+
+/// Lowers `regex` to NFA, `flags` are the ones in effect and are updated by `Regex::Flags`.
+fn lower(
+    regex: &Regex,
+    flags: &mut RegexOptions,
+    definitions: &Definitions,
+) -> Result<NFA, RegexError> {
+    Ok(match regex {
+        Regex::Empty => NFA::from(""),
+        Regex::Literal(ch) => char_to_nfa(*ch, flags),
+        Regex::Any => {
+            if flags.dot_matches_new_line {
+                NFA::from(('\0', char::MAX))
+            } else {
+                NFA::from(&[('\0', '\x09'), ('\x0b', char::MAX)][..])
+            }
+        }
+        Regex::LineStart => NFA::from(LINE_START),
+        Regex::LineEnd => NFA::from(LINE_END),
+        Regex::Class {
+            ranges,
+            negated: negate,
+        } => {
+            let mut ranges: Vec<(u32, u32)> =
+                ranges.iter().map(|&(l, r)| (l as u32, r as u32)).collect();
+            // Case folding applies before negation, so `(?i)[^a]` matches neither 'a' nor 'A'
+            if flags.case_insensitive {
+                ranges = case_fold(&ranges);
+            }
+            if *negate {
+                ranges = negated(&ranges);
+            }
+            match ranges_to_nfa(&ranges) {
+                Some(nfa) => nfa,
+                None => return Err(lowering_error(regex, RegexErrorKind::EmptyClass)),
+            }
+        }
+        Regex::Reference(name) => {
+            let key = (name.clone(), *flags);
+            if let Some(nfa) = definitions.compiled.borrow().get(&key) {
+                return Ok(nfa.clone());
+            }
+            if !definitions.contains(name) {
+                let kind = RegexErrorKind::UndefinedName(name.clone());
+                return Err(lowering_error(regex, kind));
+            }
+            // Flags set inside the definition do not leak out of it
+            let nfa = lower(&definitions.parse(name)?, &mut flags.clone(), definitions)?;
+            definitions.compiled.borrow_mut().insert(key, nfa.clone());
+            nfa
+        }
+        Regex::Group(inner) => lower(inner, &mut flags.clone(), definitions)?,
+        Regex::Flags(inline) => {
+            inline.apply(flags);
+            NFA::from("")
+        }
+        Regex::ScopedFlags(scoped, inner) => {
+            let mut inner_flags = *flags;
+            scoped.apply(&mut inner_flags);
+            lower(inner, &mut inner_flags, definitions)?
+        }
+        Regex::Repeat { regex, min, max } => {
+            let nfa = lower(regex, flags, definitions)?;
+            match (min, max) {
+                (0, None) => nfa.zero_or_more(),
+                (1, None) => nfa.one_or_more(),
+                (0, Some(1)) => nfa.optional(),
+                (&min, &max) => nfa.repeat(min, max),
+            }
+        }
+        Regex::Complement(inner) => {
+            let dfa = DFA::from(lower(inner, flags, definitions)?);
+            NFA::from(dfa.complement(Alphabet::Unicode).minimize())
+        }
+        Regex::Intersection(regexes) => {
+            let mut dfas = regexes
+                .iter()
+                .map(|regex| lower(regex, flags, definitions).map(DFA::from));
+            match dfas.next() {
+                Some(init) => {
+                    let dfa =
+                        dfas.try_fold(init?, |prev, dfa| dfa.map(|dfa| prev.intersect(&dfa)))?;
+                    NFA::from(dfa.minimize())
+                }
+                None => NFA::from(""),
+            }
+        }
+        Regex::Concat(regexes) => {
+            let mut nfas = regexes.iter().map(|regex| lower(regex, flags, definitions));
+            match nfas.next() {
+                Some(init) => nfas.try_fold(init?, |prev, nfa| nfa.map(|nfa| prev & nfa))?,
+                None => NFA::from(""),
+            }
+        }
+        Regex::Alternation(regexes) => {
+            let mut nfas = regexes.iter().map(|regex| lower(regex, flags, definitions));
+            match nfas.next() {
+                Some(init) => nfas.try_fold(init?, |prev, nfa| nfa.map(|nfa| prev | nfa))?,
+                None => NFA::new(),
+            }
+        }
+    })
+}
+
+/// Compiles a regex into NFA.
+pub fn compile_regex(regex: &str) -> Result<NFA, RegexError> {
+    compile_regex_with(regex, RegexOptions::default())
+}
+
+/// Compiles a regex into NFA with the given options, which may be overridden by inline flags.
+pub fn compile_regex_with(regex: &str, options: RegexOptions) -> Result<NFA, RegexError> {
+    compile_regex_in(regex, options, &Definitions::new())
+}
+
+/// Compiles a regex that may refer to `definitions` by `{NAME}` into NFA.
+pub fn compile_regex_in(
+    regex: &str,
+    options: RegexOptions,
+    definitions: &Definitions,
+) -> Result<NFA, RegexError> {
+    compile_regex_part(regex, options, definitions, true, true)
+}
+
+/// Same as `compile_regex_in`, but for a regex that is only a part of another one, like the
+/// regex and the trailing context of a lexer rule. `at_start` and `at_end` tell whether the edges
+/// of `regex` are edges of the other regex, anchors are only allowed at those.
+pub(crate) fn compile_regex_part(
+    regex: &str,
+    options: RegexOptions,
+    definitions: &Definitions,
+    at_start: bool,
+    at_end: bool,
+) -> Result<NFA, RegexError> {
+    parse_all(Parser::new(regex, Some(definitions)), at_start, at_end)?.to_nfa(options, definitions)
+}
+
+/// Checks whether two regexes match the same strings, compiling both with `compile_regex`.
+///
+/// Otherwise the inner result holds a shortest string matched by one of them only, see
+/// `DFA::equivalent`.
+pub fn compare_regexes(left: &str, right: &str) -> Result<Result<(), Counterexample>, RegexError> {
+    let left = DFA::from(compile_regex(left)?);
+    let right = DFA::from(compile_regex(right)?);
+    Ok(left.equivalent(&right))
 }
 
 /// Compile a regex into NFA, using only one function
 ///
 /// DEPRECATED, left here in case we might use it in the future...
-#[allow(clippy::cyclomatic_complexity)]
+#[allow(clippy::cognitive_complexity, dead_code)]
 #[deprecated()]
 fn compile_regex_deprecated(regex: &str) -> Result<NFA, &'static str> {
     // We use a simple two stack approach
@@ -499,63 +1762,310 @@ fn compile_regex_deprecated(regex: &str) -> Result<NFA, &'static str> {
         }
     }
     let mut op_stack = op_stack.borrow_mut();
-    while !op_stack.is_empty() {
-        let o = op_stack.pop().unwrap();
+    while let Some(o) = op_stack.pop() {
         pop_op(o);
     }
     let mut stack = stack.borrow_mut();
     stack.pop().ok_or("Empty regex.")
 }
 
+/// Error for a Hir that cannot be compiled, the printed Hir is taken as the pattern.
+fn unsupported_hir(hir: &Hir, kind: RegexErrorKind) -> RegexError {
+    let pattern = hir.to_string();
+    RegexError {
+        kind,
+        span: 0..pattern.len(),
+        pattern,
+    }
+}
+
 /// Compiles a Hir object in regex-syntax crate to NFA.
-/// Only some basic regex features are supported.
-/// Named captures, word boundaries and anchors are not supported.
+///
+/// Every Hir a DFA can express is supported. Greediness makes no difference to the language
+/// of a regex, so non-greedy repetitions compile like greedy ones. Line anchors compile like `^`
+/// and `$` in `compile_regex`, and like them must be at the start or the end of `hir`.
+///
+/// Anchors at the start or the end of text (`\A`, `\z`) and word boundaries (`\b`, `\B`) are
+/// reported as errors, even at the edges. The lexer runs the DFA on the chars of a token only,
+/// telling it where lines start and end with the bytes standing for line anchors, but neither
+/// whether the token is at the start or the end of the whole input nor which char comes before
+/// it. Line anchors, or a trailing context for the char after a token, cover most uses.
+///
+/// Bytes of 0x80 and above in byte literals and classes (with the `u` flag unset) match raw
+/// bytes, as in byte slices that are not UTF-8, except 0xFE and 0xFF.
 ///
 /// Auxiliary function, use this together with regex::syntax if `compile_regex` cannot satisfy your
-/// need!
-pub fn compile_hir(hir: &Hir) -> NFA {
+/// need! See also `compile_regex_syntax`.
+pub fn compile_hir(hir: &Hir) -> Result<NFA, RegexError> {
+    if let Err((_, ch)) = check_hir_anchors(hir, true, true, &mut 0) {
+        return Err(unsupported_hir(hir, RegexErrorKind::MisplacedAnchor(ch)));
+    }
+    if hir_expansion(hir) > REPETITION_LIMIT {
+        return Err(unsupported_hir(hir, RegexErrorKind::RepetitionTooLarge));
+    }
+    lower_hir(hir)
+}
+
+/// The largest number of copies of any part of `hir` made by the repetitions around it, like
+/// `expansion` does for a `Regex`.
+fn hir_expansion(hir: &Hir) -> usize {
     match hir.kind() {
+        HirKind::Repetition(Repetition { kind, hir, .. }) => {
+            let copies = match kind {
+                RepetitionKind::Range(RepetitionRange::Exactly(n))
+                | RepetitionKind::Range(RepetitionRange::AtLeast(n)) => {
+                    repetition_copies(*n as usize, None)
+                }
+                RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => {
+                    repetition_copies(*m as usize, Some(*n as usize))
+                }
+                _ => 1,
+            };
+            copies.saturating_mul(hir_expansion(hir))
+        }
+        HirKind::Group(Group { hir, .. }) => hir_expansion(hir),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => {
+            hirs.iter().map(hir_expansion).max().unwrap_or(1)
+        }
+        _ => 1,
+    }
+}
+
+/// Checks that line anchors are only at the edges of `hir` like `check_anchors` does, numbering
+/// every anchor in order by `site`.
+fn check_hir_anchors(
+    hir: &Hir,
+    at_start: bool,
+    at_end: bool,
+    site: &mut usize,
+) -> Result<(), (usize, char)> {
+    // Line anchors are not zero-width, as in `is_zero_width`, the other anchors are reported
+    // when lowered
+    let zero_width = |hir: &Hir| match hir.kind() {
+        HirKind::Empty | HirKind::WordBoundary(_) => true,
+        HirKind::Anchor(anchor) => !matches!(anchor, Anchor::StartLine | Anchor::EndLine),
+        _ => false,
+    };
+    match hir.kind() {
+        HirKind::Anchor(anchor) => {
+            *site += 1;
+            match anchor {
+                Anchor::StartLine if !at_start => return Err((*site - 1, '^')),
+                Anchor::EndLine if !at_end => return Err((*site - 1, '$')),
+                _ => {}
+            }
+        }
+        HirKind::Group(Group { hir, .. }) => check_hir_anchors(hir, at_start, at_end, site)?,
+        HirKind::Repetition(Repetition { kind, hir, .. }) => {
+            let once = match kind {
+                RepetitionKind::ZeroOrOne => true,
+                RepetitionKind::Range(RepetitionRange::Exactly(n))
+                | RepetitionKind::Range(RepetitionRange::Bounded(_, n)) => *n <= 1,
+                _ => false,
+            };
+            check_hir_anchors(hir, at_start && once, at_end && once, site)?
+        }
+        HirKind::Concat(hirs) => {
+            for (i, hir) in hirs.iter().enumerate() {
+                let start = at_start && hirs[..i].iter().all(zero_width);
+                let end = at_end && hirs[i + 1..].iter().all(zero_width);
+                check_hir_anchors(hir, start, end, site)?;
+            }
+        }
+        HirKind::Alternation(hirs) => {
+            for hir in hirs {
+                check_hir_anchors(hir, at_start, at_end, site)?;
+            }
+        }
+        HirKind::Empty | HirKind::Literal(_) | HirKind::Class(_) | HirKind::WordBoundary(_) => {}
+    }
+    Ok(())
+}
+
+/// The assertions in `ast` in order with their byte ranges. Leaving out word boundaries, they are
+/// the anchors numbered by `check_hir_anchors`.
+fn ast_assertions(ast: &Ast, assertions: &mut Vec<(AssertionKind, Range<usize>)>) {
+    match ast {
+        Ast::Assertion(assertion) => assertions.push((
+            assertion.kind.clone(),
+            assertion.span.start.offset..assertion.span.end.offset,
+        )),
+        Ast::Repetition(repetition) => ast_assertions(&repetition.ast, assertions),
+        Ast::Group(group) => ast_assertions(&group.ast, assertions),
+        Ast::Alternation(alternation) => {
+            for ast in &alternation.asts {
+                ast_assertions(ast, assertions);
+            }
+        }
+        Ast::Concat(concat) => {
+            for ast in &concat.asts {
+                ast_assertions(ast, assertions);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// What `lower_hir` reports for anchors at the start or the end of text.
+const TEXT_ANCHORS: &str =
+    "anchors at the start or the end of text (use the `m` flag for line anchors)";
+/// What `lower_hir` reports for word boundaries.
+const WORD_BOUNDARIES: &str = "word boundaries, which depend on the chars around a token,";
+
+/// Lowers `hir` to NFA, see `compile_hir`.
+fn lower_hir(hir: &Hir) -> Result<NFA, RegexError> {
+    Ok(match hir.kind() {
+        HirKind::Empty => NFA::from(""),
         HirKind::Literal(Literal::Unicode(ch)) => NFA::from(*ch),
-        HirKind::Literal(Literal::Byte(by)) => NFA::from(*by as char),
-        HirKind::Group(Group {
-            hir: inner,
-            kind: GroupKind::CaptureIndex(_),
-        }) => compile_hir(inner),
+        HirKind::Literal(Literal::Byte(LINE_START)) | HirKind::Literal(Literal::Byte(LINE_END)) => {
+            return Err(unsupported_hir(
+                hir,
+                RegexErrorKind::Unsupported(
+                    "the bytes 0xFE and 0xFF, which stand for line anchors,",
+                ),
+            ))
+        }
+        // Bytes of 0x80 and above only match as they are, not as the UTF-8 of a char
+        HirKind::Literal(Literal::Byte(by)) => NFA::from(*by),
+        HirKind::Group(Group { hir: inner, .. }) => lower_hir(inner)?,
         HirKind::Repetition(Repetition {
-            kind,
-            greedy: true,
-            hir: inner,
+            kind, hir: inner, ..
         }) => match kind {
-            RepetitionKind::OneOrMore => compile_hir(inner).one_or_more(),
-            RepetitionKind::ZeroOrMore => compile_hir(inner).zero_or_more(),
-            RepetitionKind::ZeroOrOne => compile_hir(inner).optional(),
-            _ => panic!("Repetitions within a range is not supported"),
+            RepetitionKind::OneOrMore => lower_hir(inner)?.one_or_more(),
+            RepetitionKind::ZeroOrMore => lower_hir(inner)?.zero_or_more(),
+            RepetitionKind::ZeroOrOne => lower_hir(inner)?.optional(),
+            RepetitionKind::Range(range) => {
+                let (min, max) = match *range {
+                    RepetitionRange::Exactly(n) => (n, Some(n)),
+                    RepetitionRange::AtLeast(n) => (n, None),
+                    RepetitionRange::Bounded(m, n) => (m, Some(n)),
+                };
+                lower_hir(inner)?.repeat(min as usize, max.map(|n| n as usize))
+            }
         },
         HirKind::Class(Class::Unicode(class)) => {
-            let mut iter = class.iter();
-            let first = iter.next().unwrap();
-            iter.fold(NFA::from((first.start(), first.end())), |prev, r| {
-                prev | NFA::from((r.start(), r.end()))
-            })
+            let intervals: Vec<(char, char)> = class.iter().map(|r| (r.start(), r.end())).collect();
+            if intervals.is_empty() {
+                return Err(unsupported_hir(hir, RegexErrorKind::EmptyClass));
+            }
+            NFA::from(&intervals[..])
         }
         HirKind::Class(Class::Bytes(class)) => {
-            let mut iter = class.iter();
-            let first = iter.next().unwrap();
-            iter.fold(
-                NFA::from((first.start() as char, first.end() as char)),
-                |prev, r| prev | NFA::from((r.start() as char, r.end() as char)),
-            )
+            // The bytes standing for line anchors are left out, they never occur in the input
+            let intervals: Vec<(u8, u8)> = class
+                .iter()
+                .map(|r| (r.start(), cmp::min(r.end(), LINE_END - 1)))
+                .filter(|&(l, r)| l <= r)
+                .collect();
+            if intervals.is_empty() {
+                return Err(unsupported_hir(hir, RegexErrorKind::EmptyClass));
+            }
+            NFA::from(&intervals[..])
         }
         HirKind::Alternation(v) => {
             let mut iter = v.iter();
-            let init = compile_hir(iter.next().unwrap());
-            iter.fold(init, |prev, b| prev | compile_hir(b))
+            let init = lower_hir(iter.next().unwrap())?;
+            iter.try_fold(init, |prev, b| lower_hir(b).map(|nfa| prev | nfa))?
         }
         HirKind::Concat(v) => {
             let mut iter = v.iter();
-            let init = compile_hir(iter.next().unwrap());
-            iter.fold(init, |prev, b| prev & compile_hir(b))
+            let init = lower_hir(iter.next().unwrap())?;
+            iter.try_fold(init, |prev, b| lower_hir(b).map(|nfa| prev & nfa))?
+        }
+        HirKind::Anchor(Anchor::StartLine) => NFA::from(LINE_START),
+        HirKind::Anchor(Anchor::EndLine) => NFA::from(LINE_END),
+        HirKind::Anchor(_) => {
+            return Err(unsupported_hir(
+                hir,
+                RegexErrorKind::Unsupported(TEXT_ANCHORS),
+            ))
+        }
+        HirKind::WordBoundary(_) => {
+            return Err(unsupported_hir(
+                hir,
+                RegexErrorKind::Unsupported(WORD_BOUNDARIES),
+            ))
         }
-        _ => panic!("Advanced features of regex is not supported"),
+    })
+}
+
+/// Compiles a regex into NFA with the parser of regex-syntax as the front end, see
+/// `compile_hir` for the supported features.
+///
+/// The `m` flag is set so that `^` and `$` are line anchors like in `compile_regex`, the options
+/// set the initial values of the `i` and `s` flags.
+pub fn compile_regex_syntax(regex: &str, options: RegexOptions) -> Result<NFA, RegexError> {
+    compile_regex_syntax_part(regex, options, true, true)
+}
+
+/// Same as `compile_regex_syntax`, for a part of another regex like `compile_regex_part`.
+pub(crate) fn compile_regex_syntax_part(
+    regex: &str,
+    options: RegexOptions,
+    at_start: bool,
+    at_end: bool,
+) -> Result<NFA, RegexError> {
+    let hir = ParserBuilder::new()
+        .multi_line(true)
+        .case_insensitive(options.case_insensitive)
+        .dot_matches_new_line(options.dot_matches_new_line)
+        .build()
+        .parse(regex)
+        .map_err(|e| {
+            let (message, span) = match &e {
+                regex_syntax::Error::Parse(e) => (e.kind().to_string(), e.span()),
+                regex_syntax::Error::Translate(e) => (e.kind().to_string(), e.span()),
+                _ => {
+                    return RegexError {
+                        kind: RegexErrorKind::Syntax(e.to_string()),
+                        span: 0..regex.len(),
+                        pattern: regex.to_string(),
+                    }
+                }
+            };
+            RegexError {
+                kind: RegexErrorKind::Syntax(message),
+                span: span.start.offset..span.end.offset,
+                pattern: regex.to_string(),
+            }
+        })?;
+    // Errors about assertions are located in the Ast, they come first in it as in the Hir
+    let assertions = || {
+        let mut assertions = Vec::new();
+        if let Ok(ast) = AstParser::new().parse(regex) {
+            ast_assertions(&ast, &mut assertions);
+        }
+        assertions.into_iter()
+    };
+    let is_word_boundary = |kind: &AssertionKind| {
+        matches!(
+            kind,
+            AssertionKind::WordBoundary | AssertionKind::NotWordBoundary
+        )
+    };
+    let located = |kind, span: Option<Range<usize>>| RegexError {
+        kind,
+        span: span.unwrap_or(0..regex.len()),
+        pattern: regex.to_string(),
+    };
+    if let Err((i, ch)) = check_hir_anchors(&hir, at_start, at_end, &mut 0) {
+        let span = assertions()
+            .filter(|(kind, _)| !is_word_boundary(kind))
+            .nth(i)
+            .map(|(_, span)| span);
+        return Err(located(RegexErrorKind::MisplacedAnchor(ch), span));
     }
+    compile_hir(&hir).map_err(|error| match error.kind {
+        RegexErrorKind::Unsupported(what) if what == TEXT_ANCHORS || what == WORD_BOUNDARIES => {
+            let word_boundary = what == WORD_BOUNDARIES;
+            let span = assertions()
+                .find(|(kind, _)| match kind {
+                    AssertionKind::StartLine | AssertionKind::EndLine => false,
+                    kind => is_word_boundary(kind) == word_boundary,
+                })
+                .map(|(_, span)| span);
+            located(error.kind, span)
+        }
+        _ => error,
+    })
 }
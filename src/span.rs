@@ -1,25 +1,380 @@
 //! Structs that holds locational information.
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use std::cmp;
+use std::iter;
+
+/// A position in the source text.
+///
+/// Locations are ordered by their position, which only makes sense for locations in the same
+/// text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Location {
+    /// Line number, starting from 1.
     pub line: usize,
+    /// Number of chars before the location in its line.
     pub col: usize,
+    /// Number of bytes before the location in the text.
+    pub offset: usize,
+    /// Number of chars before the location in the text.
+    pub char_offset: usize,
+}
+
+/// Which chars break lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Newlines {
+    /// Only `\n`, the default.
+    #[default]
+    Lf,
+    /// `\r\n`, `\n` or `\r`.
+    Ascii,
+    /// The same as `Ascii`, plus vertical tab, form feed, NEL (U+0085), and the line and
+    /// paragraph separators (U+2028 and U+2029).
+    Unicode,
+}
+
+impl Newlines {
+    /// Whether `ch` is (a part of) a line break.
+    #[inline]
+    pub fn is_line_break(self, ch: char) -> bool {
+        match (self, ch) {
+            (_, '\n') => true,
+            (Newlines::Lf, _) => false,
+            (_, '\r') => true,
+            (Newlines::Ascii, _) => false,
+            (Newlines::Unicode, ch) => {
+                matches!(ch, '\x0b' | '\x0c' | '\u{85}' | '\u{2028}' | '\u{2029}')
+            }
+        }
+    }
+
+    /// Whether a new line starts after `ch` followed by `next`, which is not the case for the
+    /// `\r` of `\r\n`.
+    #[inline]
+    pub fn breaks_line(self, ch: char, next: Option<char>) -> bool {
+        self.is_line_break(ch) && !(ch == '\r' && next == Some('\n'))
+    }
+
+    /// `line` without the line break it ends with, if any.
+    fn trim_line_break(self, line: &str) -> &str {
+        let mut chars = line.chars();
+        match chars.next_back() {
+            Some('\n') if self != Newlines::Lf => {
+                let rest = chars.as_str();
+                rest.strip_suffix('\r').unwrap_or(rest)
+            }
+            Some(ch) if self.is_line_break(ch) => chars.as_str(),
+            _ => line,
+        }
+    }
+}
+
+/// A way to count columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnEncoding {
+    /// Chars, as in `Location::col`.
+    Chars,
+    /// Bytes of UTF-8.
+    Bytes,
+    /// Code units of UTF-16, as positions of the Language Server Protocol are.
+    Utf16,
+    /// Columns on screen, a tab moving to the next multiple of `tab_width` and every other char
+    /// taking one column.
+    Display { tab_width: usize },
+}
+
+impl ColumnEncoding {
+    /// The number of columns the chars take in this encoding, starting from column 0.
+    pub fn width<I: IntoIterator<Item = char>>(self, chars: I) -> usize {
+        chars
+            .into_iter()
+            .fold(0, |col, ch| self.next_column(col, ch))
+    }
+
+    /// The column after `ch` found at column `col`.
+    fn next_column(self, col: usize, ch: char) -> usize {
+        match self {
+            ColumnEncoding::Chars => col + 1,
+            ColumnEncoding::Bytes => col + ch.len_utf8(),
+            ColumnEncoding::Utf16 => col + ch.len_utf16(),
+            ColumnEncoding::Display { tab_width } if ch == '\t' && tab_width > 0 => {
+                (col / tab_width + 1) * tab_width
+            }
+            ColumnEncoding::Display { .. } => col + 1,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Identifies a source text in a `SourceMap`.
+///
+/// Input lexed without a source map belongs to the default file id, which is also the id of
+/// the first file added to a map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FileId(pub usize);
+
+/// The part of the source text `file` from `from` to `to`, both inclusive, so `to` is the
+/// location of the last char.
+///
+/// Spans are ordered by file first, then by `from` and `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Span {
+    pub file: FileId,
     pub from: Location,
     pub to: Location,
 }
 
 impl Location {
-    pub fn new(line: usize, col: usize) -> Self {
-        Location { line, col }
+    pub fn new(line: usize, col: usize, offset: usize, char_offset: usize) -> Self {
+        Location {
+            line,
+            col,
+            offset,
+            char_offset,
+        }
+    }
+
+    /// The location of the first char of a text.
+    pub fn start() -> Self {
+        Location::new(1, 0, 0, 0)
+    }
+
+    /// The column of the location in `encoding`, `line` being the text of its line.
+    pub fn column(&self, line: &str, encoding: ColumnEncoding) -> usize {
+        encoding.width(line.chars().take(self.col))
+    }
+
+    /// Moves on to the location after `text`, which starts at this location and is followed by
+    /// `next`, and returns the location of the last char of `text` (this one if it is empty).
+    pub fn advance_str(&mut self, text: &str, next: Option<char>, newlines: Newlines) -> Location {
+        let mut chars = text.chars();
+        let last = match chars.next_back() {
+            Some(last) => last,
+            None => return *self,
+        };
+        let init = chars.as_str();
+        for (i, ch) in init.char_indices() {
+            // Only `\r` breaks lines depending on the char after it
+            let next = if ch == '\r' {
+                text[i + 1..].chars().next()
+            } else {
+                None
+            };
+            if newlines.breaks_line(ch, next) {
+                self.line += 1;
+                self.col = 0;
+            } else {
+                self.col += 1;
+            }
+            self.char_offset += 1;
+        }
+        self.offset += init.len();
+        let to = *self;
+        self.advance(last, next, newlines);
+        to
+    }
+
+    /// Moves on to the location after `ch`, which is the char at this location and followed by
+    /// `next`, starting a new line where `newlines` says so.
+    #[inline]
+    pub fn advance(&mut self, ch: char, next: Option<char>, newlines: Newlines) {
+        if newlines.breaks_line(ch, next) {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
+        }
+        self.offset += ch.len_utf8();
+        self.char_offset += 1;
     }
 }
 
 impl Span {
-    pub fn new(from: Location, to: Location) -> Self {
-        Span { from, to }
+    pub fn new(file: FileId, from: Location, to: Location) -> Self {
+        Span { file, from, to }
+    }
+
+    /// The text of the span in `src`, the source text it is a span of.
+    ///
+    /// Panics if the span is not in `src`.
+    pub fn slice<'a>(&self, src: &'a str) -> &'a str {
+        let last = src[self.to.offset..]
+            .chars()
+            .next()
+            .map_or(0, char::len_utf8);
+        &src[self.from.offset..self.to.offset + last]
+    }
+
+    /// The smallest span covering both spans, e.g. the span of a node of a syntax tree from
+    /// those of its children.
+    ///
+    /// Panics if the spans are in different files.
+    pub fn merge(self, other: Span) -> Span {
+        assert_eq!(
+            self.file, other.file,
+            "Cannot merge spans of different files"
+        );
+        Span::new(
+            self.file,
+            cmp::min(self.from, other.from),
+            cmp::max(self.to, other.to),
+        )
+    }
+
+    /// Whether `location` is in the span.
+    pub fn contains(&self, location: Location) -> bool {
+        self.from <= location && location <= self.to
+    }
+}
+
+/// A source text with its name, e.g. the path of the file it was read from.
+pub struct SourceFile {
+    pub name: String,
+    pub src: String,
+    /// The line breaks of the text.
+    pub newlines: Newlines,
+    /// Byte offsets of the first char of every line.
+    line_starts: Vec<usize>,
+    /// Char offsets of the first char of every line.
+    line_char_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: String, src: String, newlines: Newlines) -> Self {
+        let mut line_starts = vec![0];
+        let mut line_char_starts = vec![0];
+        let mut chars = src.char_indices().enumerate().peekable();
+        while let Some((n, (i, ch))) = chars.next() {
+            if newlines.breaks_line(ch, chars.peek().map(|&(_, (_, next))| next)) {
+                line_starts.push(i + ch.len_utf8());
+                line_char_starts.push(n + 1);
+            }
+        }
+        SourceFile {
+            name,
+            src,
+            newlines,
+            line_starts,
+            line_char_starts,
+        }
+    }
+
+    /// The number of lines of the text.
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// The text of line `line` (starting from 1) without its line break.
+    ///
+    /// Panics if there is no such line.
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.src.len(), |&next| next);
+        self.newlines.trim_line_break(&self.src[start..end])
+    }
+
+    /// The column of `location` in `encoding`.
+    pub fn column(&self, location: Location, encoding: ColumnEncoding) -> usize {
+        location.column(self.line(location.line), encoding)
+    }
+
+    /// The location at column `col` in `encoding` of line `line` (starting from 1), e.g. to
+    /// convert a position sent by a language server client. Returns `None` if there is no such
+    /// line or the column is not at the start of a char of it.
+    pub fn location_at(
+        &self,
+        line: usize,
+        col: usize,
+        encoding: ColumnEncoding,
+    ) -> Option<Location> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let text = self.line(line);
+        let mut width = 0;
+        // The chars of the line, then its end
+        for next in text.char_indices().map(Some).chain(iter::once(None)) {
+            if width == col {
+                let offset = start + next.map_or(text.len(), |(i, _)| i);
+                return Some(self.location(offset));
+            }
+            match next {
+                Some((_, ch)) if width < col => width = encoding.next_column(width, ch),
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// The location at byte `offset` of the text, which is looked up in the index of lines so
+    /// that only the chars of its line are counted.
+    ///
+    /// Panics if `offset` is out of the text or not at a char boundary.
+    pub fn location(&self, offset: usize) -> Location {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let start = self.line_starts[line];
+        let col = self.src[start..offset].chars().count();
+        Location::new(line + 1, col, offset, self.line_char_starts[line] + col)
+    }
+}
+
+/// Owns the source texts lexed by a program, which are referred to by their `FileId`s.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    newlines: Newlines,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// A source map whose texts have the line breaks of `newlines`, instead of only `\n`.
+    pub fn with_newlines(newlines: Newlines) -> Self {
+        SourceMap {
+            files: Vec::new(),
+            newlines,
+        }
+    }
+
+    /// Adds a source text and returns its id.
+    pub fn add<N, S>(&mut self, name: N, src: S) -> FileId
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        self.files
+            .push(SourceFile::new(name.into(), src.into(), self.newlines));
+        FileId(self.files.len() - 1)
+    }
+
+    /// The source text of `file`.
+    ///
+    /// Panics if `file` is not from this map.
+    pub fn file(&self, file: FileId) -> &SourceFile {
+        &self.files[file.0]
+    }
+
+    /// All the source texts with their ids.
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files.iter().enumerate().map(|(i, f)| (FileId(i), f))
+    }
+
+    /// The location at byte `offset` of `file`, see `SourceFile::location`.
+    pub fn location(&self, file: FileId, offset: usize) -> Location {
+        self.file(file).location(offset)
+    }
+
+    /// The column of `location` of `file` in `encoding`.
+    pub fn column(&self, file: FileId, location: Location, encoding: ColumnEncoding) -> usize {
+        self.file(file).column(location, encoding)
+    }
+
+    /// The text of the span.
+    pub fn slice(&self, span: &Span) -> &str {
+        span.slice(&self.file(span.file).src)
     }
 }
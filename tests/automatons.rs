@@ -0,0 +1,238 @@
+use particle::automatons::{Alphabet, BranchId, Counterexample, DFA, NFA};
+use particle::regex::{compare_regexes, compile_regex};
+
+fn dfa(regex: &str) -> DFA {
+    DFA::from(compile_regex(regex).unwrap())
+}
+
+/// DFA accepting nothing.
+fn empty() -> DFA {
+    DFA::from(NFA::new())
+}
+
+fn accepts(dfa: &DFA, input: &str) -> bool {
+    dfa.accepts(input.bytes())
+}
+
+#[test]
+fn intersect() {
+    let both = dfa("[a-z]+").intersect(&dfa("[a-c0-9]+"));
+    assert!(accepts(&both, "abc"));
+    assert!(accepts(&both, "cab"));
+    assert!(!accepts(&both, ""));
+    assert!(!accepts(&both, "abd"));
+    assert!(!accepts(&both, "a1"));
+
+    let none = dfa("a+").intersect(&dfa("b+"));
+    assert!(!accepts(&none, "a"));
+    assert!(!accepts(&none, "b"));
+    assert!(none.minimize().equivalent(&empty()).is_ok());
+}
+
+#[test]
+fn union() {
+    let either = dfa("ab").union(&dfa("c+"));
+    for input in &["ab", "c", "ccc"] {
+        assert!(accepts(&either, input), "{:?}", input);
+    }
+    for input in &["", "a", "abc", "abab"] {
+        assert!(!accepts(&either, input), "{:?}", input);
+    }
+    assert!(either.equivalent(&dfa("ab|c+")).is_ok());
+}
+
+#[test]
+fn difference() {
+    let identifiers = dfa("[a-z]+").difference(&dfa("if|else"));
+    for input in &["i", "iff", "els", "elsewhere"] {
+        assert!(accepts(&identifiers, input), "{:?}", input);
+    }
+    for input in &["", "if", "else", "If"] {
+        assert!(!accepts(&identifiers, input), "{:?}", input);
+    }
+    assert!(dfa("a*")
+        .difference(&dfa("a*"))
+        .equivalent(&empty())
+        .is_ok());
+}
+
+#[test]
+fn complement_over_bytes() {
+    let not_a = dfa("a").complement(Alphabet::Bytes);
+    assert!(!not_a.accepts(b"a".iter().cloned()));
+    for input in &[&b""[..], b"b", b"aa", b"\xff", b"a\x80", "é".as_bytes()] {
+        assert!(not_a.accepts(input.iter().cloned()), "{:?}", input);
+    }
+    let twice = not_a.complement(Alphabet::Bytes);
+    assert!(twice.equivalent(&dfa("a")).is_ok());
+}
+
+#[test]
+fn complement_over_unicode() {
+    let not_a = dfa("a").complement(Alphabet::Unicode);
+    for input in &["", "b", "aa", "é", "a\u{10FFFF}"] {
+        assert!(accepts(&not_a, input), "{:?}", input);
+    }
+    // Only valid UTF-8 is in the complement
+    for input in &[&b"a"[..], b"\xff", b"\xc3", b"a\x80", b"\xed\xa0\x80"] {
+        assert!(!not_a.accepts(input.iter().cloned()), "{:?}", input);
+    }
+    assert!(not_a
+        .complement(Alphabet::Unicode)
+        .equivalent(&dfa("a"))
+        .is_ok());
+    assert!(empty()
+        .complement(Alphabet::Unicode)
+        .equivalent(&Alphabet::Unicode.universe())
+        .is_ok());
+}
+
+fn counterexample(input: &str, accepted_by_self: bool) -> Counterexample {
+    Counterexample {
+        input: input.as_bytes().to_vec(),
+        accepted_by_self,
+    }
+}
+
+#[test]
+fn equivalent_regexes() {
+    let pairs = [
+        ("(a|b)*", "(a*b*)*"),
+        ("a(ba)*", "(ab)*a"),
+        ("\\d+", "[0-9][0-9]*"),
+        ("(ab|abab)*", "(ab)*"),
+        ("a{2,4}", "aa(aa?)?"),
+        ("(?i)k", "[kK\u{212A}]"),
+        ("[a-z]+&&~(if)", "[a-z]+&&~(if)|[a-z]{1,2}&&~if"),
+    ];
+    for &(left, right) in &pairs {
+        assert_eq!(
+            dfa(left).equivalent(&dfa(right)),
+            Ok(()),
+            "{} {}",
+            left,
+            right
+        );
+        assert_eq!(
+            compare_regexes(right, left),
+            Ok(Ok(())),
+            "{} {}",
+            right,
+            left
+        );
+    }
+}
+
+#[test]
+fn shortest_counterexamples() {
+    assert_eq!(
+        dfa("a*").equivalent(&dfa("a+")),
+        Err(counterexample("", true))
+    );
+    assert_eq!(
+        dfa("a{2,3}").equivalent(&dfa("a{2,4}")),
+        Err(counterexample("aaaa", false))
+    );
+    // The smallest one in byte order among the shortest ones
+    assert_eq!(
+        dfa("[a-c]x").equivalent(&dfa("yy|bx")),
+        Err(counterexample("ax", true))
+    );
+    assert_eq!(
+        dfa("é").equivalent(&dfa("e")),
+        Err(counterexample("e", false))
+    );
+    assert_eq!(
+        compare_regexes("(0|1)*1", "(0|1)*1(0|1)?").unwrap(),
+        Err(counterexample("10", false))
+    );
+    let message = counterexample("10", false).to_string();
+    assert_eq!(message, "\"10\" is only accepted by the second automaton");
+}
+
+#[test]
+fn is_subset_of() {
+    assert_eq!(dfa("ab+").is_subset_of(&dfa("a[a-z]*")), Ok(()));
+    assert_eq!(
+        dfa("a[a-z]*").is_subset_of(&dfa("ab+")),
+        Err(counterexample("a", true))
+    );
+    assert_eq!(dfa("if|else").is_subset_of(&dfa("[a-z]+")), Ok(()));
+    assert_eq!(
+        dfa("[a-z]+").is_subset_of(&dfa("[a-z]+&&~(if|else)")),
+        Err(counterexample("if", true))
+    );
+    assert_eq!(empty().is_subset_of(&dfa("a")), Ok(()));
+    assert_eq!(
+        dfa("(a|b)*").is_subset_of(&dfa("(a|b)+")),
+        Err(counterexample("", true))
+    );
+}
+
+/// Lexer-like DFA where the rule of every regex gets its index as branch.
+fn rules(regexes: &[&str]) -> DFA {
+    let mut nfa = NFA::new();
+    for (branch, regex) in regexes.iter().enumerate() {
+        let mut rule = compile_regex(regex).unwrap();
+        rule.set_branch(branch);
+        nfa = nfa | rule;
+    }
+    DFA::from(nfa)
+}
+
+/// The branches of the state `input` leads to, in order, `None` if it is not final.
+fn branches(dfa: &DFA, input: &str) -> Option<Vec<BranchId>> {
+    let mut state = dfa.initial_state;
+    for b in input.bytes() {
+        state = *dfa.transitions.get(&(state, b))?;
+    }
+    let mut branches: Vec<BranchId> = dfa.final_states.get(&state)?.iter().cloned().collect();
+    branches.sort();
+    Some(branches)
+}
+
+/// Every string of at most `len` chars of `alphabet`.
+fn strings(alphabet: &str, len: usize) -> Vec<String> {
+    let mut ret = vec![String::new()];
+    let mut last = ret.clone();
+    for _ in 0..len {
+        last = last
+            .iter()
+            .flat_map(|s| alphabet.chars().map(move |ch| format!("{}{}", s, ch)))
+            .collect();
+        ret.extend(last.iter().cloned());
+    }
+    ret
+}
+
+#[test]
+fn minimize_keeps_branches() {
+    let minimized = rules(&["if", "[a-z]+"]).minimize();
+    let winner = |input| branches(&minimized, input).map(|b| b[0]);
+    assert_eq!(winner("if"), Some(0));
+    assert_eq!(winner("iff"), Some(1));
+    assert_eq!(winner("i"), Some(1));
+    assert_eq!(winner(""), None);
+    assert_eq!(winner("i1"), None);
+
+    let lexers: [&[&str]; 4] = [
+        &["if", "[a-z]+"],
+        &["[a-z]+", "if"],
+        &["a+b", "a*", "(ab)+", "b"],
+        &["ab|ba", "a|b", "[ab]{2}", "a+&&~aa"],
+    ];
+    for regexes in &lexers {
+        let dfa = rules(regexes);
+        let minimized = dfa.clone().minimize();
+        assert!(minimized.final_states.len() <= dfa.final_states.len());
+        for input in strings("abfi", 5) {
+            assert_eq!(
+                branches(&dfa, &input),
+                branches(&minimized, &input),
+                "{:?} {:?}",
+                regexes,
+                input
+            );
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use particle::automatons::BranchId;
+use particle::define_lexer;
+use particle::lexer::{
+    LexError, LexErrorKind, Lexer, LexerBuilder, LexerState, Overlap, SliceState, Syntax,
+};
+use particle::regex::RegexErrorKind;
+use particle::span::{FileId, Location, Newlines, Span};
+
+/// Lexes `src` to the end with both `next_token` and `next_token_slice`, checking that they
+/// agree, and returns the tokens with the error that stopped them.
+fn lex(lexer: &Lexer<String>, src: &str, newlines: Newlines) -> (Vec<String>, LexError) {
+    let mut chars = LexerState::from(src.chars()).with_newlines(newlines);
+    let mut slice = SliceState::from(src).with_newlines(newlines);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token(&mut chars);
+        assert_eq!(
+            token,
+            lexer.next_token_slice(&mut slice),
+            "lexing {:?}",
+            src
+        );
+        match token {
+            Ok(token) => tokens.push(token),
+            Err(error) => return (tokens, error),
+        }
+    }
+}
+
+fn words() -> Lexer<String> {
+    define_lexer!(String =
+        discard "[ \t\n]+",
+        "if" => |s, _| s.to_uppercase(),
+        "[a-z]+" => |s, _| s.to_string(),
+        "[0-9]+" => |s, _| s.to_string(),
+        "->" => |s, _| s.to_string()
+    )
+}
+
+#[test]
+fn error_span_and_expected() {
+    let (tokens, error) = lex(&words(), "ab 12\n  x#y", Newlines::Lf);
+    assert_eq!(tokens, ["ab", "12", "x"]);
+    let at = Location::new(2, 3, 9, 9);
+    assert_eq!(error.span, Span::new(FileId(0), at, at));
+    assert_eq!(
+        error.kind,
+        LexErrorKind::NoMatch {
+            found: Some('#'),
+            expected: vec![('\t', '\n'), (' ', ' '), ('-', '-'), ('0', '9'), ('a', 'z')],
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        r#"expecting [\t-\n \-0-9a-z], found '#' at line 2, column 4"#
+    );
+}
+
+#[test]
+fn error_in_the_middle_of_a_token() {
+    let (tokens, error) = lex(&words(), "é -+", Newlines::Lf);
+    assert_eq!(error.span.from, Location::new(1, 0, 0, 0));
+    assert!(tokens.is_empty());
+
+    let (tokens, error) = lex(&words(), "a -+", Newlines::Lf);
+    assert_eq!(tokens, ["a"]);
+    let span = Span::new(
+        FileId(0),
+        Location::new(1, 2, 2, 2),
+        Location::new(1, 3, 3, 3),
+    );
+    assert_eq!(error.span, span);
+    assert_eq!(
+        error.kind,
+        LexErrorKind::NoMatch {
+            found: Some('+'),
+            expected: vec![('>', '>')],
+        }
+    );
+
+    let (_, error) = lex(&words(), "a -", Newlines::Lf);
+    let at = Location::new(1, 2, 2, 2);
+    assert_eq!(error.span, Span::new(FileId(0), at, at));
+    assert_eq!(
+        error.kind,
+        LexErrorKind::NoMatch {
+            found: None,
+            expected: vec![('>', '>')],
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        "expecting [>], found end of file at line 1, column 3"
+    );
+}
+
+#[test]
+fn end_of_file() {
+    let (tokens, error) = lex(&words(), "if x\n", Newlines::Lf);
+    assert_eq!(tokens, ["IF", "x"]);
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+    let at = Location::new(2, 0, 5, 5);
+    assert_eq!(error.span, Span::new(FileId(0), at, at));
+}
+
+#[test]
+fn render() {
+    let src = "ab\n\tx -+ y\n";
+    let (_, error) = lex(&words(), src, Newlines::Lf);
+    assert_eq!(
+        error.render(src),
+        "expecting [>], found '+' at line 2, column 4\n    \tx -+ y\n    \t  ^^"
+    );
+
+    let src = "a\n#";
+    let (_, error) = lex(&words(), src, Newlines::Lf);
+    assert_eq!(
+        error.render(src),
+        "expecting [\\t-\\n \\-0-9a-z], found '#' at line 2, column 1\n    #\n    ^"
+    );
+}
+
+#[test]
+fn render_with_newlines() {
+    let lexer = define_lexer!(String =
+        discard "[ \r\n\u{2028}]+",
+        "[a-z]+" => |s, _| s.to_string()
+    );
+    let found = "expecting [\\n\\r a-z\u{2028}], found '#'";
+    let src = "a\r#";
+    let (_, error) = lex(&lexer, src, Newlines::Lf);
+    assert_eq!(error.newlines, Newlines::Lf);
+    assert_eq!(error.span.from, Location::new(1, 2, 2, 2));
+    assert_eq!(
+        error.render(src),
+        format!("{} at line 1, column 3\n    a\r#\n      ^", found)
+    );
+
+    let (_, error) = lex(&lexer, src, Newlines::Ascii);
+    assert_eq!(error.newlines, Newlines::Ascii);
+    assert_eq!(error.span.from, Location::new(2, 0, 2, 2));
+    assert_eq!(
+        error.render(src),
+        format!("{} at line 2, column 1\n    #\n    ^", found)
+    );
+
+    let src = "a\u{2028}b #";
+    let (_, error) = lex(&lexer, src, Newlines::Unicode);
+    assert_eq!(error.span.from, Location::new(2, 2, 6, 4));
+    assert_eq!(
+        error.render(src),
+        format!("{} at line 2, column 3\n    b #\n      ^", found)
+    );
+
+    let (_, error) = lex(&lexer, src, Newlines::Lf);
+    assert_eq!(error.span.from, Location::new(1, 4, 6, 4));
+    assert_eq!(
+        error.render(src),
+        format!("{} at line 1, column 5\n    a\u{2028}b #\n        ^", found)
+    );
+}
+
+#[test]
+fn line_anchors() {
+    let lexer = define_lexer!(String =
+        discard "[ \n]",
+        "^[a-z]+" => |s, _| format!("^{}", s),
+        "[a-z]+$" => |s, _| format!("{}$", s),
+        "^[0-9]+$" => |s, _| format!("^{}$", s),
+        "[a-z0-9]+" => |s, _| s.to_string()
+    );
+    let (tokens, error) = lex(&lexer, "ab cd ef\n12\n 34\ngh", Newlines::Lf);
+    assert_eq!(tokens, ["^ab", "cd", "ef$", "^12$", "34", "^gh"]);
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+}
+
+#[test]
+fn repeated_line_anchors() {
+    // The lexer matches a single anchor at each edge, so these would never match
+    for &(regex, ch, at) in &[
+        ("^^a", '^', 1),
+        ("a$$", '$', 1),
+        ("^(?i)^a", '^', 5),
+        ("$^", '$', 0),
+    ] {
+        let error = LexerBuilder::<()>::new()
+            .rule(regex, |_, _| ())
+            .unwrap_err();
+        assert_eq!(error.kind, RegexErrorKind::MisplacedAnchor(ch), "{}", regex);
+        assert_eq!(error.span, at..at + 1, "{}", regex);
+    }
+    for &(regex, ch, at) in &[("^^a", '^', 1), ("a$$", '$', 1), ("(^)?^a", '^', 4)] {
+        let error = LexerBuilder::<()>::new()
+            .syntax(Syntax::RegexSyntax)
+            .rule(regex, |_, _| ())
+            .unwrap_err();
+        assert_eq!(error.kind, RegexErrorKind::MisplacedAnchor(ch), "{}", regex);
+        assert_eq!(error.span, at..at + 1, "{}", regex);
+    }
+}
+
+#[test]
+fn slice_and_chars_agree() {
+    let lexer = define_lexer!(String =
+        discard "[ \n\r]+",
+        "[a-zé]+" => |s, span| format!("{}@{}:{}", s, span.from.line, span.from.col),
+        "é+t" => |s, _| s.to_uppercase(),
+        r#""[^"\n]*""# => |_, span| format!("{}..{}", span.from.offset, span.to.offset),
+        "^#.*" => |s, _| s.to_string(),
+        ("[0-9]+", "[a-z]") => |s, _| format!("{}/", s),
+        "[0-9]+" => |s, _| s.to_string()
+    );
+    let src = "#x\r\nétét \"é\" 12a 3\n#y";
+    let (tokens, error) = lex(&lexer, src, Newlines::Lf);
+    assert_eq!(
+        tokens,
+        ["#x\r", "étét@2:0", "11..14", "12/", "a@2:11", "3", "#y"]
+    );
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+    for newlines in &[Newlines::Lf, Newlines::Ascii, Newlines::Unicode] {
+        lex(&lexer, src, *newlines);
+        lex(&lexer, "ab \"open\n", *newlines);
+        lex(&lexer, "é\u{85}#\u{2028}x ?", *newlines);
+        lex(&lexer, "", *newlines);
+    }
+}
+
+#[test]
+fn invalid_utf8() {
+    let lexer = words();
+    let mut state = SliceState::from(&b"ab \xff"[..]);
+    assert_eq!(lexer.next_token_slice(&mut state), Ok("ab".to_string()));
+    let error = lexer.next_token_slice(&mut state).unwrap_err();
+    assert_eq!(error.kind, LexErrorKind::InvalidUtf8);
+    assert_eq!(error.span.from, Location::new(1, 3, 3, 3));
+
+    // A stray continuation byte ends the token before it
+    for (src, token) in &[(&b"abc\x80"[..], "abc"), (b"a\x80", "a")] {
+        let mut state = SliceState::from(*src);
+        assert_eq!(lexer.next_token_slice(&mut state), Ok(token.to_string()));
+        let error = lexer.next_token_slice(&mut state).unwrap_err();
+        assert_eq!(error.kind, LexErrorKind::InvalidUtf8, "{:?}", src);
+        let at = token.len();
+        assert_eq!(error.span.from, Location::new(1, at, at, at));
+    }
+}
+
+#[test]
+fn fixed_trailing_context() {
+    let lexer = define_lexer!(String =
+        discard " ",
+        ("[a-z]+", "\\(") => |s, _| format!("{}()", s),
+        "[a-z]+" => |s, _| s.to_string(),
+        "[()]" => |s, _| s.to_string()
+    );
+    let (tokens, error) = lex(&lexer, "f(x) g (y)", Newlines::Lf);
+    assert_eq!(tokens, ["f()", "(", "x", ")", "g", "(", "y", ")"]);
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+}
+
+#[test]
+fn variable_trailing_context() {
+    // The token is as long as possible, leaving the rest of the match to the context
+    let lexer = define_lexer!(String =
+        discard " ",
+        ("[a-z]+", "[0-9]+[a-z]") => |s, _| format!("{}/", s),
+        "[a-z0-9]+" => |s, _| s.to_string()
+    );
+    let (tokens, _) = lex(&lexer, "abc12d ab12", Newlines::Lf);
+    assert_eq!(tokens, ["abc/", "12d", "ab12"]);
+
+    let lexer = define_lexer!(String =
+        ("a+", "a+b") => |s, _| format!("{}/", s),
+        "[ab]" => |s, _| s.to_string()
+    );
+    let (tokens, _) = lex(&lexer, "aaab", Newlines::Lf);
+    assert_eq!(tokens, ["aa/", "a", "b"]);
+}
+
+#[test]
+fn empty_trailing_context() {
+    let lexer = define_lexer!(String =
+        ("a+", "b*") => |s, _| format!("{}/", s),
+        "b" => |s, _| s.to_string()
+    );
+    let (tokens, error) = lex(&lexer, "aaa", Newlines::Lf);
+    assert_eq!(tokens, ["aaa/"]);
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+    let (tokens, _) = lex(&lexer, "aabba", Newlines::Lf);
+    assert_eq!(tokens, ["aa/", "b", "b", "a/"]);
+
+    // The context cannot leave an empty token, which would not make progress
+    let lexer = define_lexer!(String =
+        ("a*", "b") => |s, _| format!("{}/", s)
+    );
+    let (tokens, error) = lex(&lexer, "abb", Newlines::Lf);
+    assert_eq!(tokens, ["a/"]);
+    assert_eq!(error.kind, LexErrorKind::EmptyToken);
+    let span = Span::new(
+        FileId(0),
+        Location::new(1, 1, 1, 1),
+        Location::new(1, 2, 2, 2),
+    );
+    assert_eq!(error.span, span);
+}
+
+#[test]
+fn line_end_trailing_context() {
+    let lexer = define_lexer!(String =
+        discard "[ \n]",
+        ("[a-z]+", "$") => |s, _| format!("{}$", s),
+        ("[a-z]+", " *;$") => |s, _| format!("{};", s),
+        "[a-z;]+" => |s, _| s.to_string()
+    );
+    let (tokens, _) = lex(&lexer, "ab cd\nef ;\ngh", Newlines::Lf);
+    assert_eq!(tokens, ["ab", "cd$", "ef;", ";", "gh$"]);
+}
+
+#[test]
+fn anchors_between_regex_and_context() {
+    // Neither edge is an edge of the whole match, so these would never match
+    let cases = [
+        ("a$", "\n?b", '$', 1),
+        ("x", "^y", '^', 0),
+        ("(a$|b)", "c", '$', 2),
+    ];
+    for syntax in &[Syntax::Native, Syntax::RegexSyntax] {
+        for &(regex, context, ch, at) in &cases {
+            let error = LexerBuilder::<()>::new()
+                .syntax(*syntax)
+                .rule_with_context(regex, context, |_, _| ())
+                .unwrap_err();
+            assert_eq!(
+                error.kind,
+                RegexErrorKind::MisplacedAnchor(ch),
+                "{}/{}",
+                regex,
+                context
+            );
+            assert_eq!(error.span, at..at + 1, "{}/{}", regex, context);
+        }
+    }
+    let mut builder = LexerBuilder::<()>::new();
+    assert!(builder.rule_with_context("^a", "b$", |_, _| ()).is_ok());
+}
+
+#[test]
+fn many_discarded_tokens() {
+    let lexer = define_lexer!(String =
+        discard " ",
+        "[a-z]+" => |s, _| s.to_string()
+    );
+    let src = format!("{}a", " ".repeat(2_000_000));
+    let (tokens, error) = lex(&lexer, &src, Newlines::Lf);
+    assert_eq!(tokens, ["a"]);
+    assert_eq!(error.kind, LexErrorKind::EndOfFile);
+}
+
+#[test]
+fn tokens_borrowing_the_input() {
+    let lexer = words();
+    let src = String::from("if ab 12 #");
+    let tokens: Vec<_> = lexer
+        .tokens_with(src.as_str(), |branch, token, span| {
+            (branch, token, span.from.offset)
+        })
+        .collect();
+    assert_eq!(
+        tokens[..3],
+        [Ok((1, "if", 0)), Ok((2, "ab", 3)), Ok((3, "12", 6))]
+    );
+    assert_eq!(tokens.len(), 4);
+    assert!(tokens[3].is_err());
+}
+
+#[test]
+fn analyze() {
+    let lexer = define_lexer!(() =
+        discard " ",
+        "[a-z]+" => |_, _| (),
+        "if" => |_, _| (),
+        "[a-z0-9]+" => |_, _| (),
+        "a&&b" => |_, _| (),
+        "^#" => |_, _| (),
+        "#" => |_, _| ()
+    );
+    let reports = lexer.analyze();
+    let summary: Vec<(BranchId, bool)> = reports.iter().map(|r| (r.branch, r.can_win)).collect();
+    assert_eq!(
+        summary,
+        [
+            (0, true),
+            (1, true),
+            (2, false),
+            (3, true),
+            (4, false),
+            (5, true),
+            (6, true)
+        ]
+    );
+    // A fully shadowed rule overlaps with the one shadowing it
+    assert_eq!(
+        reports[2].overlaps,
+        [
+            Overlap {
+                branch: 1,
+                example: "if".to_string()
+            },
+            Overlap {
+                branch: 3,
+                example: "if".to_string()
+            },
+        ]
+    );
+    // The shortest example of an overlap, the smallest one in byte order
+    assert_eq!(
+        reports[3].overlaps[0],
+        Overlap {
+            branch: 1,
+            example: "a".to_string()
+        }
+    );
+    // A rule matching nothing is shadowed and overlaps with nothing
+    assert!(reports[4].overlaps.is_empty());
+    // Anchors are left out of the examples
+    assert_eq!(
+        reports[5].overlaps,
+        [Overlap {
+            branch: 6,
+            example: "#".to_string()
+        }]
+    );
+
+    assert_eq!(reports[0].to_string(), "rule 0");
+    assert_eq!(
+        reports[1].to_string(),
+        "rule 1 overlaps with rule 2 on \"if\", overlaps with rule 3 on \"a\""
+    );
+    assert_eq!(
+        reports[2].to_string(),
+        "rule 2 is shadowed, overlaps with rule 1 on \"if\", overlaps with rule 3 on \"if\""
+    );
+    assert_eq!(reports[4].to_string(), "rule 4 is shadowed");
+}
+
+#[test]
+fn analyze_before_building() {
+    let mut builder = LexerBuilder::<()>::new();
+    builder.rule("[a-z]+", |_, _| ()).unwrap();
+    builder.rule("x$", |_, _| ()).unwrap();
+    builder.rule("[a-z]$", |_, _| ()).unwrap();
+    let reports = builder.analyze();
+    // At a line end, the rules matching a token without `$` still win
+    assert!(reports[0].can_win);
+    assert!(!reports[1].can_win);
+    assert!(!reports[2].can_win);
+    assert_eq!(
+        reports[1].overlaps,
+        [
+            Overlap {
+                branch: 0,
+                example: "x".to_string()
+            },
+            Overlap {
+                branch: 2,
+                example: "x".to_string()
+            },
+        ]
+    );
+    assert_eq!(reports, builder.build().analyze());
+
+    let mut builder = LexerBuilder::<()>::new();
+    builder.rule("[a-z]$", |_, _| ()).unwrap();
+    builder.rule("[a-z]+", |_, _| ()).unwrap();
+    let reports = builder.analyze();
+    assert!(reports[0].can_win && reports[1].can_win);
+    assert_eq!(
+        reports[0].to_string(),
+        "rule 0 overlaps with rule 1 on \"a\""
+    );
+}
+
+#[test]
+fn peek_tokens() {
+    let lexer = words();
+    let mut tokens = lexer.tokens("if ab \n ");
+    assert_eq!(tokens.peek(), Some(&Ok("IF".to_string())));
+    assert_eq!(tokens.peek_nth(1), Some(&Ok("ab".to_string())));
+    // Trailing discarded input ends the tokens cleanly, without an end of file error
+    assert_eq!(tokens.peek_nth(2), None);
+    assert_eq!(tokens.peek_nth(5), None);
+    assert_eq!(tokens.next(), Some(Ok("IF".to_string())));
+    assert_eq!(tokens.peek(), Some(&Ok("ab".to_string())));
+    assert_eq!(tokens.next(), Some(Ok("ab".to_string())));
+    assert_eq!(tokens.next(), None);
+    assert_eq!(tokens.peek(), None);
+    assert!(tokens.state().eof());
+
+    let tokens: Vec<_> = lexer.tokens("").collect();
+    assert!(tokens.is_empty());
+}
+
+#[test]
+fn tokens_stop_after_an_error() {
+    let lexer = words();
+    let mut tokens = lexer.tokens("ab # cd");
+    let error = tokens.peek_nth(1).cloned().unwrap().unwrap_err();
+    assert_eq!(error.span.from, Location::new(1, 3, 3, 3));
+    // Nothing is lexed past the error
+    assert_eq!(tokens.peek_nth(2), None);
+    assert_eq!(tokens.state().location.offset, 3);
+    assert_eq!(tokens.next(), Some(Ok("ab".to_string())));
+    assert_eq!(tokens.next(), Some(Err(error)));
+    assert_eq!(tokens.next(), None);
+    assert_eq!(tokens.peek(), None);
+}
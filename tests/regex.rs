@@ -0,0 +1,627 @@
+use particle::automatons::DFA;
+use particle::define_lexer;
+use particle::lexer::{LexerBuilder, SliceState};
+use particle::regex::{
+    compare_regexes, compile_regex, compile_regex_in, compile_regex_syntax, compile_regex_with,
+    parse, Definitions, RegexError, RegexErrorKind, RegexOptions,
+};
+
+fn dfa(regex: &str) -> DFA {
+    DFA::from(compile_regex(regex).unwrap())
+}
+
+fn syntax_dfa(regex: &str) -> DFA {
+    DFA::from(compile_regex_syntax(regex, RegexOptions::default()).unwrap())
+}
+
+fn syntax_error(regex: &str) -> RegexError {
+    compile_regex_syntax(regex, RegexOptions::default()).unwrap_err()
+}
+
+#[test]
+fn regex_syntax_front_end() {
+    let pairs = [
+        ("[a-z]+(x|yz)?", "[a-z]+(x|yz)?"),
+        ("a+?b??", "a+b?"),
+        ("a{2,3}", "aa|aaa"),
+        ("(?i)k", "[kK\u{212A}]"),
+        ("^a|b$", "^a|b$"),
+    ];
+    for &(syntax, native) in &pairs {
+        assert_eq!(
+            syntax_dfa(syntax).equivalent(&dfa(native)),
+            Ok(()),
+            "{}",
+            syntax
+        );
+    }
+    // Options are the initial flags, `^` and `$` are line anchors
+    let options = RegexOptions {
+        case_insensitive: true,
+        dot_matches_new_line: true,
+    };
+    let nfa = compile_regex_syntax("a.", options).unwrap();
+    assert_eq!(DFA::from(nfa).equivalent(&dfa("(?is)a.")), Ok(()));
+    assert_eq!(syntax_dfa("a.").equivalent(&dfa("a.")), Ok(()));
+}
+
+#[test]
+fn regex_syntax_errors() {
+    let error = syntax_error("a(b");
+    assert!(matches!(error.kind, RegexErrorKind::Syntax(_)));
+    assert_eq!(error.span, 1..2);
+
+    // Unsupported assertions are located in the pattern
+    let cases = [
+        (r"foo\b", 3..5),
+        (r"a|\Bb", 2..4),
+        (r"\Aa", 0..2),
+        (r"(?:a\z)", 4..6),
+        (r"^a\b|\zb", 2..4),
+    ];
+    for (regex, span) in cases.iter().cloned() {
+        let error = syntax_error(regex);
+        assert!(
+            matches!(error.kind, RegexErrorKind::Unsupported(_)),
+            "{}",
+            regex
+        );
+        assert_eq!(error.span, span, "{}", regex);
+    }
+    assert_eq!(
+        syntax_error(r"foo\b").to_string(),
+        "word boundaries, which depend on the chars around a token, are not supported \
+         (at byte 3)\n    foo\\b\n       ^^"
+    );
+
+    let error = syntax_error(r"\ba^");
+    assert_eq!(error.kind, RegexErrorKind::MisplacedAnchor('^'));
+    assert_eq!(error.span, 3..4);
+}
+
+#[test]
+fn regex_syntax_rules() {
+    let lexer = define_lexer!(String =
+        discard " ",
+        "[a-z]+" => |s, _| format!("native {}", s),
+        syntax RegexSyntax,
+        r"\d+(?:\.\d+)?" => |s, _| format!("number {}", s),
+        r"(?i)x{2}" => |s, _| format!("xx {}", s)
+    );
+    let mut state = SliceState::from("ab 1.5 xXx 7");
+    let mut tokens = Vec::new();
+    while let Ok(token) = lexer.next_token_slice(&mut state) {
+        tokens.push(token);
+    }
+    assert_eq!(
+        tokens,
+        ["native ab", "number 1.5", "xx xX", "native x", "number 7"]
+    );
+}
+
+fn definitions(pairs: &[(&str, &str)]) -> Definitions {
+    let mut definitions = Definitions::new();
+    for &(name, regex) in pairs {
+        definitions.define(name, regex);
+    }
+    definitions
+}
+
+fn compile_in(regex: &str, definitions: &Definitions) -> Result<DFA, RegexError> {
+    compile_regex_in(regex, RegexOptions::default(), definitions).map(DFA::from)
+}
+
+#[test]
+fn recursive_definitions() {
+    let definitions = definitions(&[
+        ("A", "a{B}"),
+        ("B", "b|{C}"),
+        ("C", "({A})c"),
+        ("S", "x{S}?"),
+    ]);
+    // The cycle is reported where it closes, in the definition referring back to its start
+    let error = compile_in("x{C}", &definitions).unwrap_err();
+    let cycle = vec!["C", "A", "B", "C"];
+    let cycle = cycle.into_iter().map(String::from).collect();
+    assert_eq!(error.kind, RegexErrorKind::RecursiveDefinition(cycle));
+    assert_eq!((error.span, error.pattern.as_str()), (2..5, "b|{C}"));
+    assert_eq!(
+        error.kind.to_string(),
+        "recursive definition C -> A -> B -> C"
+    );
+
+    let error = compile_in("{S}", &definitions).unwrap_err();
+    let cycle = vec!["S".to_string(), "S".to_string()];
+    assert_eq!(error.kind, RegexErrorKind::RecursiveDefinition(cycle));
+    assert_eq!((error.span, error.pattern.as_str()), (1..4, "x{S}?"));
+
+    // Failed parses leave no definition on the path of the next one
+    let error = compile_in("{A}", &definitions).unwrap_err();
+    let cycle = vec!["A", "B", "C", "A"];
+    let cycle = cycle.into_iter().map(String::from).collect();
+    assert_eq!(error.kind, RegexErrorKind::RecursiveDefinition(cycle));
+    assert_eq!(error.pattern, "({A})c");
+}
+
+#[test]
+fn undefined_names() {
+    let definitions = definitions(&[("L", "[a-z]"), ("M", "{L}|{NOPE}")]);
+    let error = compile_in("a|{L}{NOPE}", &definitions).unwrap_err();
+    assert_eq!(
+        error.kind,
+        RegexErrorKind::UndefinedName("NOPE".to_string())
+    );
+    assert_eq!(error.span, 5..11);
+    assert_eq!(error.kind.to_string(), "undefined name \"NOPE\"");
+
+    // Also in definitions, and when lowering a regex parsed without definitions
+    let error = compile_in("{M}", &definitions).unwrap_err();
+    assert_eq!((error.span, error.pattern.as_str()), (4..10, "{L}|{NOPE}"));
+    let regex = parse("x{NOPE}").unwrap();
+    let error = regex
+        .to_nfa(RegexOptions::default(), &definitions)
+        .unwrap_err();
+    assert_eq!(
+        error.kind,
+        RegexErrorKind::UndefinedName("NOPE".to_string())
+    );
+}
+
+#[test]
+fn definitions_under_flags() {
+    let mut definitions = definitions(&[("K", "k"), ("IL", "(?i)l")]);
+    // The NFA of a definition is cached for every set of flags it is lowered with
+    let cased = compile_in("{K}(?i:{K})", &definitions).unwrap();
+    assert!(cased.accepts("kK".bytes()));
+    assert!(!cased.accepts("Kk".bytes()));
+    let folded = compile_in("(?i){K}{K}", &definitions).unwrap();
+    assert!(folded.accepts("KK".bytes()));
+    assert!(compile_in("{K}", &definitions)
+        .unwrap()
+        .equivalent(&DFA::from(compile_regex("k").unwrap()))
+        .is_ok());
+
+    // Flags set in a definition do not leak out of it
+    let leaked = compile_in("{IL}l", &definitions).unwrap();
+    assert!(leaked.accepts("Ll".bytes()));
+    assert!(!leaked.accepts("lL".bytes()));
+
+    // Redefining a name drops its cached NFAs
+    definitions.define("K", "q");
+    assert!(compile_in("{K}", &definitions)
+        .unwrap()
+        .accepts("q".bytes()));
+}
+
+#[test]
+fn define_in_lexer() {
+    let lexer = define_lexer!(String =
+        define DIGIT = "[0-9]",
+        define NUMBER = "{DIGIT}+(\\.{DIGIT}+)?",
+        discard " ",
+        "{NUMBER}" => |s, _| format!("number {}", s),
+        "[a-z]{DIGIT}*" => |s, _| format!("name {}", s)
+    );
+    let mut state = SliceState::from("1.5 x12 42");
+    let mut tokens = Vec::new();
+    while let Ok(token) = lexer.next_token_slice(&mut state) {
+        tokens.push(token);
+    }
+    assert_eq!(tokens, ["number 1.5", "name x12", "number 42"]);
+}
+
+#[test]
+fn print_and_parse_again() {
+    let regexes = [
+        // Classes with the chars special in them
+        r"[\]a]",
+        r"[\^a]",
+        r"[a^]",
+        r"[a-]",
+        r"[-a]",
+        r"[\]-\^]",
+        r"[^\]\-\^]",
+        r"[\x00-\x1f]",
+        // Scoped and inline flags
+        "(?i:ab)c",
+        "(?i)a(?-i)b",
+        "(?is:.(?-s).)",
+        "a(?i)(b|c)d",
+        // Precedence of intersection and complement
+        "a|b&&c",
+        "~a&&b",
+        "~(ab)",
+        "(~a)b",
+        "~a*",
+        "(~a)*",
+        "(a|b)&&~(ab)c",
+        // Control chars and escapes
+        "\t\n\r\\x01\\u{7f}\\0",
+        "\\u{2028}é\\.\\*",
+        "a{2,3}(bc)?d{4,}",
+    ];
+    for &regex in &regexes {
+        let parsed = parse(regex).unwrap();
+        let printed = parsed.to_string();
+        assert_eq!(
+            parse(&printed),
+            Ok(parsed),
+            "{} printed as {}",
+            regex,
+            printed
+        );
+        assert_eq!(
+            compare_regexes(regex, &printed),
+            Ok(Ok(())),
+            "{} printed as {}",
+            regex,
+            printed
+        );
+    }
+
+    let printed = |regex| parse(regex).unwrap().to_string();
+    assert_eq!(printed("[a-]"), r"[\-a]");
+    assert_eq!(printed(r"\x01[\x00-\x1f]"), r"\u{1}[\0-\u{1f}]");
+    assert_eq!(printed("~(ab)"), "~(ab)");
+    assert_eq!(printed("(~a)*"), "(~a)*");
+}
+
+fn error(regex: &str) -> RegexError {
+    compile_regex(regex).unwrap_err()
+}
+
+#[test]
+fn counted_repetitions() {
+    let pairs = [
+        ("a{3}", "aaa"),
+        ("a{2,}", "aaa*"),
+        ("a{0,2}", "a?a?"),
+        ("a{0}b", "b"),
+        ("(ab){1,2}", "ab(ab)?"),
+        ("\\\\u[0-9a-f]{4}", "\\\\u[0-9a-f][0-9a-f][0-9a-f][0-9a-f]"),
+    ];
+    for &(counted, spelled) in &pairs {
+        assert_eq!(compare_regexes(counted, spelled), Ok(Ok(())), "{}", counted);
+        let hir = compile_regex_syntax(counted, RegexOptions::default()).unwrap();
+        assert_eq!(
+            DFA::from(hir).equivalent(&dfa(spelled)),
+            Ok(()),
+            "{}",
+            counted
+        );
+    }
+
+    // Counts are written without spaces
+    for &(regex, at) in &[("a{2, 3}", 4), ("a{ 2}", 2), ("a{2 }", 3), ("a{,3}", 2)] {
+        let error = error(regex);
+        assert!(
+            matches!(error.kind, RegexErrorKind::Expected { .. }),
+            "{}",
+            regex
+        );
+        assert_eq!(error.span, at..at + 1, "{}", regex);
+    }
+    assert_eq!(error("a{3,2}").kind, RegexErrorKind::InvalidRepetition);
+    assert_eq!(error("a{3,2}").span, 1..6);
+}
+
+#[test]
+fn repetition_limit() {
+    // Up to 1000 copies of any part of a regex, counting nested repetitions together
+    for regex in &[
+        "a{1000}",
+        "a{0,1000}",
+        "a{1000,}",
+        "(a{10}){100}",
+        "(a{10}|b){100}",
+    ] {
+        assert!(compile_regex(regex).is_ok(), "{}", regex);
+    }
+    let cases = [
+        ("a{1001}", 1..7),
+        ("a{999,1001}", 1..11),
+        ("(a{10}){101}", 7..12),
+        ("b(a{2}){2}{251}", 10..15),
+        ("a{99999999999999999999}", 1..23),
+    ];
+    for (regex, span) in cases.iter().cloned() {
+        let error = error(regex);
+        assert_eq!(error.kind, RegexErrorKind::RepetitionTooLarge, "{}", regex);
+        assert_eq!(error.span, span, "{}", regex);
+    }
+    assert_eq!(
+        RegexErrorKind::RepetitionTooLarge.to_string(),
+        "repetition count (multiplied by nested ones) is larger than the limit 1000"
+    );
+
+    // Also through definitions and in the syntax of regex-syntax
+    let definitions = definitions(&[("TEN", "a{10}")]);
+    let error = compile_in("{TEN}{101}", &definitions).unwrap_err();
+    assert_eq!(error.kind, RegexErrorKind::RepetitionTooLarge);
+    assert!(compile_in("{TEN}{100}", &definitions).is_ok());
+    let error = compile_regex_syntax("(a{10}){101}", RegexOptions::default()).unwrap_err();
+    assert_eq!(error.kind, RegexErrorKind::RepetitionTooLarge);
+}
+
+/// Checks that every pair of regexes matches the same strings.
+fn assert_equivalent(pairs: &[(&str, &str)]) {
+    for &(left, right) in pairs {
+        assert_eq!(
+            compare_regexes(left, right),
+            Ok(Ok(())),
+            "{} {}",
+            left,
+            right
+        );
+    }
+}
+
+#[test]
+fn shorthand_and_posix_classes() {
+    assert_equivalent(&[
+        ("\\d", "[0-9]"),
+        ("\\w", "[0-9A-Za-z_]"),
+        ("\\s", "[\t\n\\x0b\\x0c\r ]"),
+        ("\\D", "[^0-9]"),
+        ("\\W", "[^0-9A-Za-z_]"),
+        ("\\S", "[^\t\n\\x0b\\x0c\r ]"),
+        ("[[:alpha:]]", "[A-Za-z]"),
+        ("[[:^alpha:]]", "[^A-Za-z]"),
+        ("[^[:alpha:]]", "[^A-Za-z]"),
+        ("[^[:^alpha:]]", "[A-Za-z]"),
+        ("[[:xdigit:][:space:]]", "[0-9A-Fa-f\t-\r ]"),
+        // Inside brackets, with other items and negation
+        ("[\\d_]", "[0-9_]"),
+        ("[^\\d]", "\\D"),
+        ("[^\\D]", "\\d"),
+        ("\\w&&\\D|[[:digit:]a]", "[A-Z_a-z]|[0-9a]"),
+        // A class cannot be the end of a range, so the '-' next to it is literal
+        ("[\\d-z]", "[0-9z\\-]"),
+        ("[[:alpha:]-]", "[A-Za-z\\-]"),
+        // Outside brackets `[:alpha:]` is a plain class
+        ("[:alpha:]", "[:ahlp]"),
+    ]);
+    // Negated classes cover every char but the ones of the class
+    let digits = dfa("\\D");
+    for input in &["a", "\u{10FFFF}", "😀", "\u{FFFD}"] {
+        assert!(digits.accepts(input.bytes()), "{:?}", input);
+    }
+
+    let unknown = error("[[:foo:]]");
+    assert_eq!(
+        unknown.kind,
+        RegexErrorKind::UnknownPosixClass("foo".to_string())
+    );
+    assert_eq!(unknown.span, 1..8);
+    let endpoint = error("[a-\\d]");
+    assert_eq!(endpoint.kind, RegexErrorKind::ClassEscape);
+    assert_eq!(endpoint.span, 3..5);
+}
+
+#[test]
+fn unicode_properties() {
+    let letters = dfa("\\p{L}");
+    for input in &["a", "é", "Ж", "中", "𝒜"] {
+        assert!(letters.accepts(input.bytes()), "{:?}", input);
+    }
+    for input in &["1", "-", " ", "😀", "ab"] {
+        assert!(!letters.accepts(input.bytes()), "{:?}", input);
+    }
+    let greek = dfa("\\p{Greek}");
+    assert!(greek.accepts("α".bytes()));
+    assert!(!greek.accepts("a".bytes()));
+
+    assert_equivalent(&[
+        ("\\pL", "\\p{L}"),
+        ("\\p{Letter}", "\\p{L}"),
+        ("\\p{gc=Lu}", "\\p{Lu}"),
+        ("\\p{sc=Greek}", "\\p{Script=Greek}"),
+        ("\\P{L}", "[^\\p{L}]"),
+        ("[^\\P{L}]", "\\p{L}"),
+        ("\\p{L}|\\P{L}", "(?s)."),
+        ("\\p{Nd}&&[\\0-\\x7f]", "[0-9]"),
+        ("[\\p{Lu}\\d]&&[\\0-\\x7f]", "[A-Z0-9]"),
+    ]);
+
+    // Identifiers in the way of Unicode
+    let identifiers = dfa("\\p{XID_Start}\\p{XID_Continue}*");
+    for input in &["x", "été", "变量1", "a_b"] {
+        assert!(identifiers.accepts(input.bytes()), "{:?}", input);
+    }
+    for input in &["_a", "1a", "a-b"] {
+        assert!(!identifiers.accepts(input.bytes()), "{:?}", input);
+    }
+
+    // Large tables stay compact once minimized
+    let states = |dfa: DFA| {
+        let minimized = dfa.minimize();
+        let mut states: Vec<_> = minimized.transitions.keys().map(|&(x, _)| x).collect();
+        states.sort();
+        states.dedup();
+        states.len()
+    };
+    assert!(states(dfa("\\p{L}")) < 400);
+    assert!(states(dfa("\\P{L}")) < 400);
+    assert!(states(dfa("\\p{XID_Continue}")) < 400);
+
+    let unknown = error("a\\p{Foo}");
+    assert_eq!(
+        unknown.kind,
+        RegexErrorKind::UnknownProperty("Foo".to_string())
+    );
+    assert_eq!(unknown.span, 1..8);
+    assert!(matches!(
+        error("\\p{L").kind,
+        RegexErrorKind::Expected { .. }
+    ));
+}
+
+#[test]
+fn whole_unicode_range() {
+    let string = dfa(r#""[^"\\]*""#);
+    for input in &["\"😀\"", "\"\u{10000}\u{10FFFF}\"", "\"\u{FFFF}é\n\""] {
+        assert!(string.accepts(input.bytes()), "{:?}", input);
+    }
+    let dot = dfa(".");
+    for input in &["a", "\u{FFFF}", "\u{10000}", "😀", "\u{10FFFF}"] {
+        assert!(dot.accepts(input.bytes()), "{:?}", input);
+        assert!(string.accepts(format!("\"{}\"", input).bytes()));
+    }
+    // Surrogates are not chars, so their UTF-8 encoding is not matched
+    for negated in &[&dot, &dfa("[^a]"), &dfa("\\P{L}")] {
+        assert!(!negated.accepts(vec![0xed, 0xa0, 0x80]));
+        assert!(!negated.accepts(vec![0xf4, 0x90, 0x80, 0x80]));
+    }
+
+    // `.` matches a newline with the `s` flag only, which is unset by default
+    assert!(!dot.accepts("\n".bytes()));
+    assert!(dfa("(?s).").accepts("\n".bytes()));
+    assert!(!dfa("(?s:a)(?-s).").accepts("a\n".bytes()));
+    assert_equivalent(&[(".", "[^\n]"), ("(?s).", "[\\0-\\u{10FFFF}]")]);
+    let options = RegexOptions {
+        dot_matches_new_line: true,
+        ..RegexOptions::default()
+    };
+    let dot_all = DFA::from(compile_regex_with(".", options).unwrap());
+    assert!(dot_all.accepts("\n".bytes()));
+    let dot_line = DFA::from(compile_regex_with("(?-s).", options).unwrap());
+    assert!(!dot_line.accepts("\n".bytes()));
+}
+
+#[test]
+fn astral_chars_in_lexer() {
+    let lexer = define_lexer!(String =
+        discard "[ \n]",
+        r#""[^"\\]*""# => |s, _| s.to_string(),
+        "." => |s, _| format!("any {}", s)
+    );
+    let mut state = SliceState::from("\"a😀\" 😀\n\u{10FFFF}");
+    let mut tokens = Vec::new();
+    while let Ok(token) = lexer.next_token_slice(&mut state) {
+        tokens.push(token);
+    }
+    assert_eq!(tokens, ["\"a😀\"", "any 😀", "any \u{10FFFF}"]);
+}
+
+#[test]
+fn error_spans() {
+    let cases = [
+        ("a(b", RegexErrorKind::UnclosedGroup, 1..2),
+        ("a)b", RegexErrorKind::UnopenedGroup, 1..2),
+        ("[ab", RegexErrorKind::UnclosedClass, 0..3),
+        ("é[z-a]", RegexErrorKind::InvalidInterval, 3..6),
+        ("(?q)", RegexErrorKind::UnknownFlag('q'), 2..3),
+        ("[]", RegexErrorKind::EmptyClass, 0..2),
+        (
+            "ab|*",
+            RegexErrorKind::Expected {
+                expected: "a character",
+                found: Some('*'),
+            },
+            3..4,
+        ),
+    ];
+    for (regex, kind, span) in cases.iter().cloned() {
+        let error = error(regex);
+        assert_eq!((error.kind, error.span), (kind, span), "{}", regex);
+        assert_eq!(error.pattern, regex);
+    }
+}
+
+#[test]
+fn error_carets() {
+    // The caret is under the span, counting chars rather than bytes
+    assert_eq!(
+        error("é[z-a]").to_string(),
+        "invalid interval, the end is less than the start (at byte 3)\n    é[z-a]\n      ^^^"
+    );
+    // An empty span at the end of the pattern still gets a caret
+    assert_eq!(
+        error("a\\").to_string(),
+        "expecting a character after escape '\\', found end of pattern (at byte 2)\n    a\\\n      ^"
+    );
+    assert_eq!(
+        error("ab|*").to_string(),
+        "expecting a character, found '*' (at byte 3)\n    ab|*\n       ^"
+    );
+}
+
+#[test]
+#[should_panic(expected = "Cannot compile the regex of lexer rule 2: unclosed group")]
+fn error_in_lexer_rule() {
+    define_lexer!(() =
+        discard " ",
+        "[a-z]+" => |_, _| (),
+        "a(b" => |_, _| ()
+    );
+}
+
+#[test]
+fn error_in_builder_rule() {
+    let mut builder = LexerBuilder::<()>::new();
+    builder.rule("[a-z]+", |_, _| ()).unwrap();
+    let error = builder.rule("[0-9", |_, _| ()).unwrap_err();
+    assert_eq!(
+        (error.kind, error.span),
+        (RegexErrorKind::UnclosedClass, 0..4)
+    );
+    // A failed rule takes no branch
+    assert_eq!(builder.next_branch(), 1);
+}
+
+#[test]
+fn case_insensitive() {
+    assert_equivalent(&[
+        ("(?i)select", "[sSſ][eE][lL][eE][cC][tT]"),
+        ("(?i)k", "[kK\u{212A}]"),
+        ("(?i)σ", "[σςΣ]"),
+        ("(?i)[a-c]", "[a-cA-C]"),
+        ("(?i)[[:upper:]]", "[a-zA-Z\u{17F}\u{212A}]"),
+        ("(?i)\\d", "\\d"),
+        // Case folding comes before negation
+        ("(?i)[^a]", "[^aA]"),
+        // Scoped and inline flags
+        ("(?i:a)b", "[aA]b"),
+        ("a(?i)b(?-i)c", "a[bB]c"),
+        ("(?i)a(b|(?-i:c))", "[aA]([bB]|c)"),
+    ]);
+    let options = RegexOptions {
+        case_insensitive: true,
+        ..RegexOptions::default()
+    };
+    let folded = DFA::from(compile_regex_with("ab(?-i)c", options).unwrap());
+    assert_eq!(folded.equivalent(&dfa("[aA][bB]c")), Ok(()));
+}
+
+#[test]
+fn case_insensitive_rules() {
+    let lexer = define_lexer!(String =
+        discard " ",
+        ignore_case "select|from" => |s, _| format!("keyword {}", s),
+        "[a-zA-Z]+" => |s, _| format!("name {}", s)
+    );
+    let mut state = SliceState::from("SELECT a FroM selects");
+    let mut tokens = Vec::new();
+    while let Ok(token) = lexer.next_token_slice(&mut state) {
+        tokens.push(token);
+    }
+    assert_eq!(
+        tokens,
+        ["keyword SELECT", "name a", "keyword FroM", "name selects"]
+    );
+
+    let mut builder = LexerBuilder::new();
+    builder.rule("Case", |s, _| s.to_string()).unwrap();
+    let options = RegexOptions {
+        case_insensitive: true,
+        ..RegexOptions::default()
+    };
+    builder
+        .rule_with("case", options, |s, _| s.to_lowercase())
+        .unwrap();
+    let lexer = builder.build();
+    for &(input, token) in &[("Case", "Case"), ("CASE", "case"), ("case", "case")] {
+        let token = Ok(token.to_string());
+        assert_eq!(lexer.next_token_slice(&mut SliceState::from(input)), token);
+    }
+}
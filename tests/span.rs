@@ -0,0 +1,126 @@
+use particle::define_lexer;
+use particle::lexer::{Lexer, SliceState};
+use particle::span::{ColumnEncoding, FileId, Location, Newlines, SourceMap, Span};
+
+/// Lexer keeping the span of every token.
+fn spans() -> Lexer<Span> {
+    define_lexer!(Span =
+        discard "[ \t\r\n]+",
+        "[^ \t\r\n]+" => |_, span| span
+    )
+}
+
+fn lex_spans(map: &SourceMap, file: FileId) -> Vec<Span> {
+    let lexer = spans();
+    let mut state = SliceState::in_source(map, file);
+    let mut spans = Vec::new();
+    while let Ok(span) = lexer.next_token_slice(&mut state) {
+        spans.push(span);
+    }
+    spans
+}
+
+#[test]
+fn line_index_with_crlf() {
+    let mut map = SourceMap::with_newlines(Newlines::Ascii);
+    let file = map.add("crlf", "ab\r\ncd\r\n\r\ne\rf");
+    let source = map.file(file);
+    assert_eq!(source.line_count(), 5);
+    let lines: Vec<&str> = (1..=5).map(|line| source.line(line)).collect();
+    assert_eq!(lines, ["ab", "cd", "", "e", "f"]);
+
+    // The `\r\n` is a single line break, both chars are at the end of the line
+    assert_eq!(source.location(2), Location::new(1, 2, 2, 2));
+    assert_eq!(source.location(3), Location::new(1, 3, 3, 3));
+    assert_eq!(source.location(4), Location::new(2, 0, 4, 4));
+    assert_eq!(source.location(8), Location::new(3, 0, 8, 8));
+    assert_eq!(source.location(12), Location::new(5, 0, 12, 12));
+    assert_eq!(source.location(13), Location::new(5, 1, 13, 13));
+
+    // Only `\n` breaks lines by default, so `\r` stays in the line
+    let mut map = SourceMap::new();
+    let file = map.add("lf", "ab\r\ncd\re");
+    let source = map.file(file);
+    assert_eq!(source.line_count(), 2);
+    assert_eq!(source.line(1), "ab\r");
+    assert_eq!(source.line(2), "cd\re");
+    assert_eq!(source.location(7), Location::new(2, 3, 7, 7));
+}
+
+#[test]
+fn spans_agree_with_source_map() {
+    let mut map = SourceMap::with_newlines(Newlines::Ascii);
+    let first = map.add("first", "x");
+    let second = map.add("second", "ab\r\n é😀z\r\rq\n");
+    assert_eq!((first, second), (FileId(0), FileId(1)));
+    let spans = lex_spans(&map, second);
+    let tokens: Vec<&str> = spans.iter().map(|span| map.slice(span)).collect();
+    assert_eq!(tokens, ["ab", "é😀z", "q"]);
+    for span in &spans {
+        assert_eq!(span.file, second);
+        assert_eq!(span.from, map.location(second, span.from.offset));
+        assert_eq!(span.to, map.location(second, span.to.offset));
+    }
+    assert_eq!(spans[1].from, Location::new(2, 1, 5, 5));
+    assert_eq!(spans[1].to, Location::new(2, 3, 11, 7));
+    assert_eq!(spans[2].from, Location::new(4, 0, 14, 10));
+
+    let spans = lex_spans(&map, first);
+    assert_eq!(
+        spans,
+        [Span::new(first, Location::start(), Location::start())]
+    );
+}
+
+#[test]
+fn utf16_columns() {
+    let mut map = SourceMap::new();
+    let file = map.add("astral", "x\na😀b𝒜c");
+    let source = map.file(file);
+    // The location of 'b', after a char of two UTF-16 code units and four bytes
+    let b = source.location(7);
+    assert_eq!(b, Location::new(2, 2, 7, 4));
+    assert_eq!(source.column(b, ColumnEncoding::Chars), 2);
+    assert_eq!(source.column(b, ColumnEncoding::Bytes), 5);
+    assert_eq!(source.column(b, ColumnEncoding::Utf16), 3);
+    let c = source.location(12);
+    assert_eq!(source.column(c, ColumnEncoding::Utf16), 6);
+    assert_eq!(map.column(file, c, ColumnEncoding::Chars), 4);
+
+    assert_eq!(source.location_at(2, 3, ColumnEncoding::Utf16), Some(b));
+    assert_eq!(source.location_at(2, 6, ColumnEncoding::Utf16), Some(c));
+    assert_eq!(source.location_at(2, 5, ColumnEncoding::Bytes), Some(b));
+    // The end of the line is a location too
+    let end = source.location_at(2, 7, ColumnEncoding::Utf16).unwrap();
+    assert_eq!(end.offset, 13);
+    // Inside a surrogate pair, or past the end of the line
+    assert_eq!(source.location_at(2, 2, ColumnEncoding::Utf16), None);
+    assert_eq!(source.location_at(2, 5, ColumnEncoding::Utf16), None);
+    assert_eq!(source.location_at(2, 8, ColumnEncoding::Utf16), None);
+    assert_eq!(source.location_at(2, 3, ColumnEncoding::Bytes), None);
+    assert_eq!(source.location_at(3, 0, ColumnEncoding::Utf16), None);
+    assert_eq!(source.location_at(0, 0, ColumnEncoding::Utf16), None);
+}
+
+#[test]
+fn tab_stops() {
+    let mut map = SourceMap::new();
+    let file = map.add("tabs", "\ta\t\tb c\td");
+    let source = map.file(file);
+    let display = ColumnEncoding::Display { tab_width: 4 };
+    let columns: Vec<usize> = [1, 4, 6, 8]
+        .iter()
+        .map(|&offset| source.column(source.location(offset), display))
+        .collect();
+    assert_eq!(columns, [4, 12, 14, 16]);
+    let wide = ColumnEncoding::Display { tab_width: 8 };
+    assert_eq!(source.column(source.location(4), wide), 24);
+    assert_eq!(wide.width("ab\tc".chars()), 9);
+
+    assert_eq!(source.location_at(1, 12, display), Some(source.location(4)));
+    assert_eq!(source.location_at(1, 0, display), Some(source.location(0)));
+    // Inside a tab
+    for col in &[1, 3, 6, 9, 11] {
+        assert_eq!(source.location_at(1, *col, display), None, "{}", col);
+    }
+}